@@ -0,0 +1,737 @@
+use std::io::Write;
+use std::path::Path;
+
+use crate::hex::*;
+use crate::{exit_with_error, make_update, status};
+
+// -- Options shared by the subcommands -- //
+#[derive(clap::Args, Debug)]
+pub struct LoadArgs {
+    /// How to handle overlapping (or duplicate) data records
+    #[arg(long, value_enum, default_value_t = OverlapPolicy::Error)]
+    overlap: OverlapPolicy,
+
+    /// Flash program granularity, in bytes (or one of: stm32f4, stm32g4, stm32h7)
+    #[arg(short, long, value_name = "BYTES", value_parser = target::parse_granularity)]
+    granularity: Option<Target>,
+
+    /// Device profile, for the program granularity and flash pages (built-in name, or TOML/JSON file)
+    #[arg(short, long, value_name = "NAME|FILE")]
+    device: Option<String>,
+
+    /// The image ends with an appended CRC32 (no padding of the final packet)
+    #[arg(short, long)]
+    append_crc: bool,
+}
+
+impl LoadArgs {
+    fn device(&self) -> Option<Device> {
+        self.device
+            .as_ref()
+            .map(|name| Device::load(name).unwrap_or_else(|e| exit_with_error(e)))
+    }
+
+    fn target(&self, device: Option<&Device>) -> Target {
+        match (self.granularity, device) {
+            (Some(target), _) => target,
+            (None, Some(device)) => device.target(),
+            (None, None) => Target::default(),
+        }
+    }
+
+    fn read(&self, path: &str) -> Vec<Region> {
+        input::read_hex_file(Path::new(path), self.overlap).unwrap_or_else(|e| exit_with_error(e))
+    }
+}
+
+pub(crate) fn print_regions(regions: &[Region]) {
+    for r in regions.iter() {
+        status!(" - Region: ADDR = {:08x}, SIZE = {}", r.address(), r.len());
+    }
+}
+
+pub(crate) fn print_packet(p: &FirmwareUpdatePacket) {
+    status!(
+        " - Packet: ADDR = {:08x}, SIZE = {}, CRC16 = 0x{:04X}",
+        p.address(),
+        p.len(),
+        p.crc16()
+    );
+}
+
+// -- info -- //
+#[derive(clap::Args, Debug)]
+pub struct InfoArgs {
+    #[arg(value_name = "IN.HEX")]
+    file: String,
+
+    /// Print a memory map: the regions, the gaps (and how they are filled), and any padding or CRC32
+    #[arg(long)]
+    map: bool,
+
+    /// Print a hexdump ('hexdump -C' style) of the address range ('START..END' or 'START+SIZE')
+    #[arg(long, value_name = "RANGE", value_parser = range::parse_range)]
+    dump: Option<AddressRange>,
+
+    #[command(flatten)]
+    load: LoadArgs,
+
+    /// Verbosity of generated output?
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    verbose: u8,
+}
+
+/**
+ * Print the regions (before and after merging), the length, CRC32, and
+ * SHA-256 of the firmware update, and the entry point: from the start linear
+ * address record, if there is one, or else the reset vector (the second word
+ * of the Cortex-M vector table, at the base of the image).
+ */
+pub fn info(args: InfoArgs) {
+    let text = stdio::read_input_text(Path::new(&args.file)).unwrap_or_else(|e| {
+        exit_with_error(Error::Input(format!("cannot read '{}' ({})", args.file, e)))
+    });
+    let start = input::start_address(&input::read_records(&text));
+    let regions =
+        input::read_hex_text(&text, args.load.overlap).unwrap_or_else(|e| exit_with_error(e));
+    let device = args.load.device();
+    let target = args.load.target(device.as_ref());
+
+    status!("\nFound {} HEX regions", regions.len());
+    print_regions(&regions);
+    let merged = merge_regions(&regions, &target);
+    if args.verbose > 0 {
+        status!("\nMerged into {} HEX regions", merged.len());
+        print_regions(&merged);
+    }
+
+    let update = make_update(&regions, &target, args.load.append_crc);
+    status!("\nFirmware update:");
+    status!(" - Length: {}", update.len());
+    status!(" - CRC32:  0x{:08X}", update.crc32());
+    status!(
+        " - {}: {}",
+        DigestAlg::Sha256.name(),
+        digest::to_hex_string(&update.digest(DigestAlg::Sha256))
+    );
+    if args.verbose > 0 {
+        status!(" - Packets: {}", update.packets().len());
+    }
+
+    let reset = update.to_region().and_then(|image| {
+        let vector = image.data().get(4..8)?;
+        Some(u32::from_le_bytes(vector.try_into().unwrap()))
+    });
+    match (start, reset) {
+        (Some(address), _) => status!(" - Entry:  0x{:08x} (start address record)", address),
+        (None, Some(address)) => status!(" - Entry:  0x{:08x} (reset vector)", address),
+        (None, None) => (),
+    }
+
+    if args.map {
+        status!("\nMemory map:");
+        let entries = map::memory_map(&regions, &target, args.load.append_crc)
+            .unwrap_or_else(|e| exit_with_error(e));
+        for e in entries {
+            status!(" - {}  {:<5} {:>8}", e.range, e.kind.name(), e.range.len());
+        }
+    }
+    if let Some(range) = &args.dump {
+        status!("\nHexdump of {}:", range);
+        for line in map::hexdump(&regions, range) {
+            status!("{}", line);
+        }
+    }
+}
+
+// -- verify -- //
+#[derive(clap::Args, Debug)]
+pub struct VerifyArgs {
+    /// Image to check: a HEX file, or a BIN file (e.g. a dump read back from a device)
+    #[arg(value_name = "IN.HEX|IN.BIN")]
+    file: String,
+
+    /// Base address of a BIN image (which is implied, for any file, when given)
+    #[arg(long, value_name = "ADDR", value_parser = range::parse_address)]
+    base: Option<u32>,
+
+    /// Length of the image data, within a BIN image (e.g. a whole flash slot)
+    #[arg(long, value_name = "BYTES", value_parser = range::parse_address)]
+    length: Option<u32>,
+
+    /// Expected CRC32 of the firmware update
+    #[arg(
+        long,
+        value_name = "CRC32",
+        value_parser = range::parse_address,
+        required_unless_present_any = ["reference", "append_crc"]
+    )]
+    crc32: Option<u32>,
+
+    /// Reference HEX file, that the image must match
+    #[arg(long, value_name = "REF.HEX", conflicts_with = "crc32")]
+    reference: Option<String>,
+
+    #[command(flatten)]
+    load: LoadArgs,
+}
+
+/**
+ * Recompute the CRC32 of the image, as for the BIN output (so that the final
+ * packet is zero-padded to the program granularity, unless a CRC32 was
+ * appended), and check it against the appended CRC32 (with '--append-crc'),
+ * and against the expected value, or the CRC32 of the reference image. Exits
+ * with an error if any of these differ.
+ */
+pub fn verify(args: VerifyArgs) {
+    let device = args.load.device();
+    let target = args.load.target(device.as_ref());
+    let append_crc = args.load.append_crc;
+    let image = verify::read_image(Path::new(&args.file), args.base, args.load.overlap)
+        .unwrap_or_else(|e| exit_with_error(e));
+    let length = args.length.map(|n| n as usize);
+    let (image, appended) = match (append_crc, length) {
+        (true, _) => {
+            let (image, crc32) = verify::split_appended_crc(&image, length, &target)
+                .unwrap_or_else(|e| exit_with_error(e));
+            (image, Some(crc32))
+        }
+        (false, Some(n)) => {
+            let end = u32::try_from(n)
+                .ok()
+                .and_then(|n| image.address().checked_add(n))
+                .unwrap_or_else(|| {
+                    exit_with_error(Error::Verify(format!(
+                        "image at 0x{:08x} cannot hold {} bytes",
+                        image.address(),
+                        n
+                    )))
+                });
+            match image.clip(&AddressRange::new(image.address(), end)) {
+                Some(image) if image.len() == n => (image, None),
+                _ => exit_with_error(Error::Verify(format!("image is shorter than {} bytes", n))),
+            }
+        }
+        (false, None) => (image, None),
+    };
+    let update = make_update(&[image], &target, append_crc);
+
+    if let Some(crc32) = appended {
+        if crc32 != update.crc32() {
+            exit_with_error(Error::Verify(format!(
+                "appended CRC32 0x{:08X} does not match the data (0x{:08X})",
+                crc32,
+                update.crc32()
+            )));
+        }
+    }
+    let expected = match (&args.reference, args.crc32) {
+        (Some(path), _) => {
+            let reference = make_update(&args.load.read(path), &target, append_crc);
+            if reference.crc32() != update.crc32() {
+                let offset = verify::first_difference(&update.to_vec(), &reference.to_vec());
+                exit_with_error(Error::Verify(format!(
+                    "CRC32 0x{:08X} does not match 0x{:08X} ('{}'), first difference at offset 0x{:x}",
+                    update.crc32(),
+                    reference.crc32(),
+                    path,
+                    offset.unwrap_or(0)
+                )));
+            }
+            Some(reference.crc32())
+        }
+        (None, Some(crc32)) => Some(crc32),
+        (None, None) => None,
+    };
+    if let Some(crc32) = expected {
+        if crc32 != update.crc32() {
+            exit_with_error(Error::Verify(format!(
+                "CRC32 0x{:08X} does not match 0x{:08X} (expected)",
+                update.crc32(),
+                crc32
+            )));
+        }
+    }
+    status!("\nVerified '{}':", args.file);
+    status!(" - Length: {}", update.len());
+    status!(" - CRC32:  0x{:08X}", update.crc32());
+    if appended.is_some() {
+        status!(" - Appended CRC32 matches");
+    }
+}
+
+// -- check-header -- //
+#[derive(clap::Args, Debug)]
+pub struct CheckHeaderArgs {
+    #[arg(value_name = "IN.H")]
+    include: String,
+
+    /// HEX file, that the C include file must hold the image of
+    #[arg(short = 'x', long, value_name = "IN.HEX")]
+    hex: String,
+
+    /// Prefix of the names of the C constants
+    #[arg(long, value_name = "NAME", default_value = append::DEFAULT_SYMBOL_PREFIX)]
+    symbol_prefix: String,
+
+    #[command(flatten)]
+    load: LoadArgs,
+}
+
+/**
+ * Parse a (previously generated) C include file, and check that its declared
+ * CRC32 matches its bytes, and that both match what would be generated from
+ * the HEX file now.
+ */
+pub fn check_header(args: CheckHeaderArgs) {
+    let text = stdio::read_input_text(Path::new(&args.include)).unwrap_or_else(|e| {
+        exit_with_error(Error::Input(format!(
+            "cannot read '{}' ({})",
+            args.include, e
+        )))
+    });
+    let image = header::parse_include_text(&text, &args.symbol_prefix)
+        .unwrap_or_else(|e| exit_with_error(e));
+    let device = args.load.device();
+    let target = args.load.target(device.as_ref());
+    let update = make_update(&args.load.read(&args.hex), &target, args.load.append_crc);
+    let text = to_include_text(&update, args.load.append_crc, &[], &args.symbol_prefix);
+    let expect = header::parse_include_text(&text, &args.symbol_prefix)
+        .unwrap_or_else(|e| exit_with_error(e));
+
+    if let Some(offset) = verify::first_difference(&image.bytes, &expect.bytes) {
+        let byte = |bytes: &[u8]| match bytes.get(offset) {
+            Some(x) => format!("0x{:02x}", x),
+            None => "none".to_string(),
+        };
+        let hint = match image.bytes.len().abs_diff(expect.bytes.len()) {
+            4 => ", see '--append-crc'",
+            _ => "",
+        };
+        exit_with_error(Error::Header(format!(
+            "data differs from '{}' at offset 0x{:x} ({} != {}{})",
+            args.hex,
+            offset,
+            byte(&image.bytes),
+            byte(&expect.bytes),
+            hint
+        )));
+    }
+    let crc32 = hexcrc::stm32_crc(&image.bytes);
+    if image.crc32 != crc32 {
+        exit_with_error(Error::Header(format!(
+            "declared CRC32 0x{:08X} does not match the data (0x{:08X})",
+            image.crc32, crc32
+        )));
+    }
+    if image.crc32 != expect.crc32 {
+        exit_with_error(Error::Header(format!(
+            "declared CRC32 0x{:08X} does not match '{}' (0x{:08X}, see '--append-crc')",
+            image.crc32, args.hex, expect.crc32
+        )));
+    }
+    status!("\nChecked '{}' against '{}':", args.include, args.hex);
+    status!(" - Length: {}", image.bytes.len());
+    status!(" - CRC32:  0x{:08X}", image.crc32);
+}
+
+// -- diff -- //
+#[derive(clap::Args, Debug)]
+pub struct DiffArgs {
+    #[arg(value_name = "OLD.HEX")]
+    old: String,
+
+    #[arg(value_name = "NEW.HEX")]
+    new: String,
+
+    /// Flash page size (default: from the device profile, or 2048)
+    #[arg(long, value_name = "BYTES", value_parser = delta::parse_page_size)]
+    page_size: Option<u32>,
+
+    /// Write the packets of the changed pages
+    #[arg(long, value_name = "OUT.PKT")]
+    delta: Option<String>,
+
+    /// Write the changed pages, and the length and CRC32 of the new image, as JSON
+    #[arg(long, value_name = "OUT.JSON")]
+    delta_list: Option<String>,
+
+    /// Print a side-by-side hexdump (old | new) of each change
+    #[arg(long)]
+    hexdump: bool,
+
+    #[command(flatten)]
+    load: LoadArgs,
+
+    /// Verbosity of generated output?
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    verbose: u8,
+}
+
+/**
+ * Report the address ranges whose data was added, removed, or changed, and
+ * the packets that differ, and then compare the two images flash page by
+ * flash page, as for a delta update.
+ */
+pub fn diff(args: DiffArgs) {
+    crate::route_status(&[&args.delta, &args.delta_list], false);
+    let device = args.load.device();
+    let target = args.load.target(device.as_ref());
+    let (old_regions, new_regions) = (args.load.read(&args.old), args.load.read(&args.new));
+    // The flash pages of the delta only cover the device's flash banks
+    if let Some(device) = &device {
+        for (path, regions) in [(&args.old, &old_regions), (&args.new, &new_regions)] {
+            device
+                .validate(regions)
+                .unwrap_or_else(|e| exit_with_error(Error::Input(format!("'{}': {}", path, e))));
+        }
+    }
+    let changes = diff::compare_regions(&old_regions, &new_regions);
+    let count = |kind| {
+        changes
+            .iter()
+            .filter(|c| c.kind == kind)
+            .fold(0, |s, c| s + c.range.len())
+    };
+    status!("\nChanges from '{}' to '{}':", args.old, args.new);
+    status!(" - Added:   {} bytes", count(diff::ChangeKind::Added));
+    status!(" - Removed: {} bytes", count(diff::ChangeKind::Removed));
+    status!(" - Changed: {} bytes", count(diff::ChangeKind::Changed));
+    for c in changes.iter() {
+        status!(
+            " - {}: ADDR = {:08x}, SIZE = {}",
+            c.kind.name(),
+            c.range.start(),
+            c.range.len()
+        );
+    }
+
+    let old = make_update(&old_regions, &target, args.load.append_crc);
+    let new = make_update(&new_regions, &target, args.load.append_crc);
+    let indices = diff::changed_packets(old.packets(), new.packets());
+    status!(
+        " - Packets: {} of {}",
+        indices.len(),
+        old.packets().len().max(new.packets().len())
+    );
+    if !indices.is_empty() {
+        status!(" - Packet indices: {}", diff::to_index_list(&indices));
+    }
+    status!(" - CRC32:   0x{:08X} -> 0x{:08X}", old.crc32(), new.crc32());
+
+    if args.hexdump && !changes.is_empty() {
+        let ranges: Vec<AddressRange> = changes.iter().map(|c| c.range).collect();
+        status!("\nHexdump (old | new):");
+        for line in diff::side_by_side(&old_regions, &new_regions, &ranges) {
+            status!("{}", line);
+        }
+    }
+
+    let pages = match args.page_size {
+        Some(_) => None,
+        None => device.as_ref(),
+    };
+    let page_size = args.page_size.unwrap_or(delta::DEFAULT_PAGE_SIZE);
+    let delta = Delta::new(&old, &new, pages, page_size, &target);
+    status!("\nChanged flash pages:");
+    report_delta(&delta, args.verbose);
+    write_delta(&delta, &args.delta, &args.delta_list, args.verbose);
+}
+
+pub(crate) fn report_delta(delta: &Delta, verbose: u8) {
+    let length = delta.packets().iter().fold(0, |s, p| s + p.len());
+    status!(
+        " - Pages:  {} of {}",
+        delta.pages().len(),
+        delta.page_count()
+    );
+    status!(" - Length: {} (of {})", length, delta.len());
+    status!(" - CRC32:  0x{:08X} (new image)", delta.crc32());
+    if verbose > 0 {
+        for p in delta.pages() {
+            status!(" - Page: ADDR = {:08x}, SIZE = {}", p.address, p.size);
+        }
+    }
+}
+
+pub(crate) fn write_delta(
+    delta: &Delta,
+    packets: &Option<String>,
+    list: &Option<String>,
+    verbose: u8,
+) {
+    if let Some(filename) = packets {
+        if verbose > 0 {
+            status!("\nWriting '{}'", filename);
+        }
+        to_packet_file(filename, delta.packets());
+    }
+    if let Some(filename) = list {
+        if verbose > 0 {
+            status!("\nWriting '{}'", filename);
+        }
+        stdio::write_output(filename, delta.to_json()).unwrap();
+    }
+}
+
+// -- patch -- //
+#[derive(clap::Args, Debug)]
+pub struct PatchArgs {
+    #[arg(value_name = "IN.HEX")]
+    file: String,
+
+    #[arg(short, long, value_name = "OUT.HEX")]
+    output: String,
+
+    /// Write bytes into the image, e.g. '0x0801f800=DEADBEEF'
+    #[arg(long, value_name = "ADDR=HEX-BYTES", value_parser = patch::parse_bytes_patch)]
+    set: Vec<Patch>,
+
+    /// Write a 32-bit value into the image, e.g. '0x0801f800=1234'
+    #[arg(long, value_name = "ADDR=VALUE", value_parser = patch::parse_u32_patch)]
+    set_u32: Vec<(u32, u32)>,
+
+    /// Read patches from a file ('set ADDR=HEX-BYTES' or 'set-u32 ADDR=VALUE' lines)
+    #[arg(long, value_name = "FILE")]
+    patch_file: Option<String>,
+
+    /// Byte-order for 32-bit patch values
+    #[arg(long, value_enum, default_value_t = Endian::Little)]
+    endian: Endian,
+
+    /// Allow patches to add data where the image has none
+    #[arg(long)]
+    extend: bool,
+
+    /// How to handle overlapping (or duplicate) data records
+    #[arg(long, value_enum, default_value_t = OverlapPolicy::Error)]
+    overlap: OverlapPolicy,
+}
+
+/**
+ * Apply the patches, and write the patched image as a HEX file, keeping the
+ * gaps between its regions (nothing is padded, or merged).
+ */
+pub fn patch(args: PatchArgs) {
+    crate::route_status(&[&Some(args.output.clone())], false);
+    let text = stdio::read_input_text(Path::new(&args.file)).unwrap_or_else(|e| {
+        exit_with_error(Error::Input(format!("cannot read '{}' ({})", args.file, e)))
+    });
+    let start = input::start_address(&input::read_records(&text));
+    let mut regions =
+        input::read_hex_text(&text, args.overlap).unwrap_or_else(|e| exit_with_error(e));
+    let patches = patch::collect_patches(&args.set, &args.set_u32, &args.patch_file, args.endian)
+        .unwrap_or_else(|e| exit_with_error(e));
+    patch::apply_patches(&mut regions, &patches, args.extend)
+        .unwrap_or_else(|e| exit_with_error(e));
+
+    let target = Target::default();
+    let packets = regions
+        .iter()
+        .flat_map(|r| r.to_packets(&target, false))
+        .collect();
+    let update = FirmwareUpdate::new(packets);
+    to_hex_file(&args.output, &update, false, start);
+    status!("\nApplied {} patches", patches.len());
+    status!(" - Writing '{}'", args.output);
+}
+
+// -- send -- //
+#[derive(clap::Args, Debug)]
+pub struct SendArgs {
+    #[arg(value_name = "IN.HEX")]
+    file: String,
+
+    /// Port (device file) to write the packets to, e.g. '/dev/ttyUSB0'
+    #[arg(long, value_name = "PORT")]
+    port: String,
+
+    /// Delay after each packet, in milliseconds
+    #[arg(long, value_name = "MS", default_value_t = 0)]
+    delay_ms: u64,
+
+    /// Precede the data packets with erase-command packets (requires a device profile)
+    #[arg(long, requires = "device")]
+    erase_packets: bool,
+
+    #[command(flatten)]
+    load: LoadArgs,
+
+    /// Verbosity of generated output?
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    verbose: u8,
+}
+
+/**
+ * Write the packets (as for '--packets') to the port, one at a time. The port
+ * must already be configured (e.g. its baud-rate, using 'stty'), and there is
+ * no handshake, so the delay must allow the bootloader to program each packet.
+ */
+pub fn send(args: SendArgs) {
+    let device = args.load.device();
+    let target = args.load.target(device.as_ref());
+    let regions = args.load.read(&args.file);
+    let update = make_update(&regions, &target, args.load.append_crc);
+    let mut packets = match &device {
+        Some(device) if args.erase_packets => {
+            let regions = merge_regions(&regions, &target);
+            let plan = ErasePlan::new(device, &regions).unwrap_or_else(|e| exit_with_error(e));
+            plan.to_packets()
+        }
+        _ => Vec::new(),
+    };
+    packets.extend_from_slice(update.packets());
+
+    let err = |e: std::io::Error| Error::Send(format!("'{}' ({})", args.port, e));
+    let mut port = std::fs::OpenOptions::new()
+        .write(true)
+        .open(&args.port)
+        .unwrap_or_else(|e| exit_with_error(err(e)));
+    for p in packets.iter() {
+        if args.verbose > 1 {
+            print_packet(p);
+        }
+        port.write_all(&p.to_bytes())
+            .and_then(|_| port.flush())
+            .unwrap_or_else(|e| exit_with_error(err(e)));
+        std::thread::sleep(std::time::Duration::from_millis(args.delay_ms));
+    }
+    status!("\nSent '{}' to '{}':", args.file, args.port);
+    status!(" - Packets: {}", packets.len());
+    status!(" - Length:  {}", update.len());
+    status!(" - CRC32:   0x{:08X}", update.crc32());
+}
+
+// -- convert (the default command) -- //
+pub(crate) fn report_erase_plan(plan: &ErasePlan, device: &Device, verbose: u8) {
+    status!("\nErase plan for '{}':", device.name());
+    status!(" - Sectors: {}", plan.sectors().len());
+    status!(" - Length:  {}", plan.size());
+    for r in plan.ranges() {
+        status!(" - Erase: ADDR = {:08x}, SIZE = {}", r.start(), r.len());
+    }
+    if verbose > 0 {
+        for s in plan.sectors() {
+            status!(
+                " - Sector: BANK = {}, INDEX = {}, ADDR = {:08x}, SIZE = {}",
+                s.bank + 1,
+                s.index,
+                s.address,
+                s.size
+            );
+        }
+    }
+}
+
+pub(crate) fn report_update(update: &FirmwareUpdate, digests: &[DigestAlg]) {
+    status!("\nFirmware update:");
+    status!(" - Length: {}", update.len());
+    status!(" - CRC32:  0x{:08X}", update.crc32());
+    for alg in digests.iter() {
+        status!(
+            " - {}: {}",
+            alg.name(),
+            digest::to_hex_string(&update.digest(*alg))
+        );
+    }
+    status!();
+}
+
+/**
+ * Write the include, binary, and HEX outputs, each with its own options,
+ * building (and reporting) another firmware update for those outputs whose
+ * CRC32 option differs from that of the 'image'.
+ */
+pub(crate) fn write_outputs(
+    specs: &[OutputSpec],
+    image: &output::Image,
+    summary: &mut Summary,
+    verbose: u8,
+) {
+    for (format, spec) in specs.iter().filter_map(|x| Some((x.format.image()?, x))) {
+        let append_crc = spec.append_crc.unwrap_or(image.append_crc);
+        let variant;
+        let (update, trailer) = match append_crc == image.append_crc {
+            true => (image.update, image.trailer),
+            false => {
+                variant = image.variant(append_crc);
+                status!(
+                    "Image for '{}' ({} CRC32):",
+                    spec.path,
+                    if append_crc { "with" } else { "without" }
+                );
+                status!(" - Length: {}", variant.0.len());
+                status!(" - CRC32:  0x{:08X}", variant.0.crc32());
+                status!();
+                (&variant.0, &variant.1[..])
+            }
+        };
+        if verbose > 0 {
+            status!("\nWriting '{}'", &spec.path);
+        }
+        image.write(format, spec, update, trailer);
+        summary.add_output(spec.format.name(), &spec.path);
+    }
+}
+
+/**
+ * Erase-command packets for the sectors of the erase plan; with a delta
+ * update, for only those sectors that overlap the pages that are to be
+ * re-programmed (which may differ in size from the sectors).
+ */
+pub(crate) fn erase_packets(
+    plan: &ErasePlan,
+    kept: Option<&[delta::Page]>,
+) -> Vec<FirmwareUpdatePacket> {
+    let mut plan = plan.clone();
+    if let Some(kept) = kept {
+        let ranges: Vec<AddressRange> = kept.iter().filter_map(|p| p.range()).collect();
+        plan.retain_overlapping(&ranges);
+    }
+    plan.to_packets()
+}
+
+/**
+ * Compressed packets of the image, after checking that they decompress to
+ * the image.
+ */
+pub(crate) fn compress_packets(
+    image: &Region,
+    format: Compression,
+    target: &Target,
+) -> Vec<FirmwareUpdatePacket> {
+    let packets = compress::compressed_packets(image, format, target);
+    let stream: Vec<u8> = packets[1..].iter().flat_map(|p| p.to_vec()).collect();
+    let bytes = format
+        .decompress(&stream, image.len())
+        .unwrap_or_else(|e| exit_with_error(e));
+    if let Some(offset) = verify::first_difference(&bytes, image.data()) {
+        exit_with_error(Error::Compression(format!(
+            "round-trip check failed, at offset 0x{:x}",
+            offset
+        )));
+    }
+    status!("Compression ({}):", format.name());
+    status!(" - Length: {} (of {})", stream.len(), image.len());
+    status!(" - Packets: {}", packets.len());
+    status!();
+    packets
+}
+
+pub(crate) fn write_packet_files(
+    specs: &[OutputSpec],
+    packets: &[FirmwareUpdatePacket],
+    summary: &mut Summary,
+    verbose: u8,
+) {
+    if specs.iter().any(|x| x.format == OutputFormat::Packets) {
+        summary.set_stream(packets);
+    }
+    for spec in specs.iter().filter(|x| x.format == OutputFormat::Packets) {
+        if verbose > 0 {
+            status!("\nWriting '{}'", spec.path);
+        }
+        to_packet_file(&spec.path, packets);
+        summary.add_output(spec.format.name(), &spec.path);
+    }
+}
@@ -1,5 +1,10 @@
 use lazy_static::lazy_static;
 
+use super::digest::DigestAlg;
+use super::hexcrc::stm32_crc;
+use super::packet::FirmwareUpdatePacket;
+use super::stdio::write_output;
+use super::target::Target;
 use super::update::FirmwareUpdate;
 
 //
@@ -34,11 +39,14 @@ const CRC32_COMMENT: &str = "/**
  *    firmware Git repository, and this can be built using 'make', if a suitable
  *    GNU build environment has been set up;
  */\n";
-const CRC32_DECLARE: &str = "const uint32_t kBootloaderFirmwareCrc = 0x";
-const IMAGE_DECLARE: &str = "ul;\n\nconst uint8_t kBootloaderFirmwareBin[] = {\n\t";
+/// Prefix of the names of the C constants, e.g. 'kBootloaderFirmwareCrc'.
+pub const DEFAULT_SYMBOL_PREFIX: &str = "kBootloaderFirmware";
+pub(crate) const CRC32_SUFFIX: &str = "Crc";
+pub(crate) const IMAGE_SUFFIX: &str = "Bin";
 const IMAGE_COMPLETE: &str = "\n};\n";
 
 const MAX_COLUMNS: usize = 12;
+const HEX_RECORD_LENGTH: usize = 16;
 
 // Global (and lazily-initialised) store for all device labels, and counters
 lazy_static! {
@@ -81,47 +89,92 @@ fn hex_bytes(values: &[u8]) -> String {
     bytes
 }
 
-pub fn to_include_text(update: &FirmwareUpdate, append_crc: bool) -> String {
-    let alg = crc::Crc::<u32>::new(&super::hexcrc::CUSTOM_ALG);
-    let mut dig = alg.digest();
-    let mut raw: Vec<u8> = Vec::with_capacity(update.len() + 4);
+/**
+ * CRC32 that the C include file declares: of the firmware data, followed by
+ * the appended CRC32 (if any). For an encrypted update, this is the CRC32 of
+ * the plaintext, which the device checks after decryption.
+ */
+pub fn include_crc32(update: &FirmwareUpdate, append_crc: bool) -> u32 {
+    match append_crc && !update.is_encrypted() {
+        true => {
+            let mut bytes = update.to_vec();
+            bytes.extend(update.crc32().to_le_bytes());
+            stm32_crc(&bytes)
+        }
+        false => update.crc32(),
+    }
+}
 
+pub fn to_include_text(
+    update: &FirmwareUpdate,
+    append_crc: bool,
+    digests: &[DigestAlg],
+    prefix: &str,
+) -> String {
+    let mut raw: Vec<u8> = Vec::with_capacity(update.len() + 4);
     for p in update.packets() {
-        let mut dat = p.to_vec();
-        dig.update(&dat);
-        raw.append(&mut dat);
+        raw.append(&mut p.to_vec());
     }
-
-    let crc32: u32 = if append_crc {
-        let bs = update.crc32().to_ne_bytes();
-        dig.update(&bs);
-        raw.extend(&bs);
-        dig.finalize()
-    } else {
-        update.crc32()
-    };
+    if append_crc {
+        raw.extend(update.crc32().to_le_bytes());
+    }
+    let crc32 = include_crc32(update, append_crc);
 
     let bytes: String = hex_bytes(&raw);
     let mut contents: String = HEADER_COMMENT.to_string();
     contents.push_str(HEADER_INCLUDE);
     contents.push_str(CRC32_COMMENT);
-    contents.push_str(&format!("{}{:08X}", CRC32_DECLARE, crc32));
-    contents.push_str(IMAGE_DECLARE);
+    contents.push_str(&format!(
+        "const uint32_t {}{} = 0x{:08X}ul;",
+        prefix, CRC32_SUFFIX, crc32
+    ));
+    for alg in digests.iter() {
+        // Digest of the firmware data, excluding any appended CRC32
+        let digest = update.digest(*alg);
+        contents.push_str(&format!(
+            "\n\nconst uint8_t {}{}[{}] = {{\n\t{}\n}};",
+            prefix,
+            alg.label(),
+            digest.len(),
+            hex_bytes(&digest)
+        ));
+    }
+    contents.push_str(&format!(
+        "\n\nconst uint8_t {}{}[] = {{\n\t",
+        prefix, IMAGE_SUFFIX
+    ));
     contents.push_str(&bytes);
     contents.push_str(IMAGE_COMPLETE);
     contents
 }
 
-pub fn to_include_file(filename: &str, update: &FirmwareUpdate, append_crc: bool) {
-    let bytes = to_include_text(update, append_crc);
-    std::fs::write(filename, &bytes).unwrap()
+pub fn to_include_file(
+    filename: &str,
+    update: &FirmwareUpdate,
+    append_crc: bool,
+    digests: &[DigestAlg],
+    prefix: &str,
+) {
+    let bytes = to_include_text(update, append_crc, digests, prefix);
+    write_output(filename, &bytes).unwrap()
 }
 
-pub fn to_binary_file(filename: &str, update: &FirmwareUpdate, append_crc: bool) {
+/**
+ * Write the firmware data as a binary file, followed by the CRC32 (if
+ * 'append_crc' is set), and then by the 'trailer' bytes (e.g., digests and a
+ * signature), padded to the program granularity.
+ */
+pub fn to_binary_file(
+    filename: &str,
+    update: &FirmwareUpdate,
+    append_crc: bool,
+    trailer: &[u8],
+    target: &Target,
+) {
     let len: usize = if append_crc {
-        (update.len() + 11) & !0x07
+        target.align_up(update.len() + 4 + trailer.len())
     } else {
-        (update.len() + 7) & !0x07
+        target.align_up(update.len() + trailer.len())
     };
     let alg = crc::Crc::<u32>::new(&super::hexcrc::CUSTOM_ALG);
     let mut dig = alg.digest();
@@ -131,20 +184,138 @@ pub fn to_binary_file(filename: &str, update: &FirmwareUpdate, append_crc: bool)
         dig.update(&dat);
         bytes.append(&mut dat);
     }
-    assert!(update.crc32() == dig.finalize());
+    assert!(update.is_encrypted() || update.crc32() == dig.finalize());
     if append_crc {
         let crc = update.crc32();
         let byt = update.crc32().to_ne_bytes();
-        println!(
+        crate::status!(
             "Appending '0x{:08X}ul' to the Lt Sensor bootloader (length = {})",
-            crc, len
+            crc,
+            len
         );
         // Check that the host system is Little Endian
         assert!(byt[0] as u32 == crc & 0x0ff);
         bytes.extend(&byt);
     }
+    bytes.extend(trailer);
     while bytes.len() < len {
         bytes.push(0);
     }
-    std::fs::write(filename, &bytes).unwrap()
+    write_output(filename, &bytes).unwrap()
+}
+
+pub fn to_packet_file(filename: &str, packets: &[FirmwareUpdatePacket]) {
+    let bytes: Vec<u8> = packets.iter().flat_map(|p| p.to_bytes()).collect();
+    write_output(filename, bytes).unwrap()
+}
+
+/**
+ * Build the data records (with extended linear address records, as needed)
+ * for a contiguous block of data.
+ */
+fn hex_records(records: &mut Vec<ihex::Record>, segment: &mut Option<u16>, addr: u32, data: &[u8]) {
+    let mut addr = addr;
+    let mut data = data;
+    while !data.is_empty() {
+        let upper = (addr >> 16) as u16;
+        if *segment != Some(upper) {
+            records.push(ihex::Record::ExtendedLinearAddress(upper));
+            *segment = Some(upper);
+        }
+        // Records must not cross a 64 kB boundary
+        let room = 0x1_0000 - (addr & 0xffff) as usize;
+        let size = data.len().min(HEX_RECORD_LENGTH).min(room);
+        records.push(ihex::Record::Data {
+            offset: addr as u16,
+            value: data[0..size].to_vec(),
+        });
+        addr += size as u32;
+        data = &data[size..];
+    }
+}
+
+/**
+ * Intel HEX text of the firmware data, followed by the CRC32 (if
+ * 'append_crc' is set), and by a start linear address record (if 'start' is
+ * given, e.g. from that of the input).
+ */
+pub fn to_hex_text(update: &FirmwareUpdate, append_crc: bool, start: Option<u32>) -> String {
+    let mut records = Vec::new();
+    let mut segment = None;
+    let mut next: u32 = 0;
+    for p in update.packets() {
+        hex_records(&mut records, &mut segment, p.address(), &p.to_vec());
+        next = p.address() + p.len() as u32;
+    }
+    if append_crc {
+        let bytes = update.crc32().to_le_bytes();
+        hex_records(&mut records, &mut segment, next, &bytes);
+    }
+    records.extend(start.map(ihex::Record::StartLinearAddress));
+    records.push(ihex::Record::EndOfFile);
+    ihex::create_object_file_representation(&records).unwrap()
+}
+
+pub fn to_hex_file(filename: &str, update: &FirmwareUpdate, append_crc: bool, start: Option<u32>) {
+    let text = to_hex_text(update, append_crc, start);
+    write_output(filename, text).unwrap()
+}
+
+//----------------------------------------------------------------------------
+// Tests
+//----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::{include_crc32, to_hex_text, to_include_text, DEFAULT_SYMBOL_PREFIX};
+    use crate::digest::DigestAlg;
+    use crate::hexcrc::stm32_crc;
+    use crate::input::{read_records, start_address};
+    use crate::region::Region;
+    use crate::update::FirmwareUpdate;
+
+    #[test]
+    fn hex_output_round_trip() {
+        // Crosses a 64 kB boundary, so needs two extended linear address records
+        let data: Vec<u8> = (0..500).map(|x| x as u8).collect();
+        let region = Region::with_data(0x0800_ff00, data.clone());
+        let update = FirmwareUpdate::new(region.to_packets(&Default::default(), false));
+        let text = to_hex_text(&update, true, Some(0x0800_ff09));
+
+        let records = read_records(&text);
+        assert_eq!(start_address(&records), Some(0x0800_ff09));
+        let mut records: Vec<ihex::Record> = records.into_iter().map(|(_, r)| r).collect();
+        let regions = Region::build_regions(&mut records);
+        let mono = Region::single_region(&regions).unwrap();
+        let mut expect = data;
+        expect.extend(update.crc32().to_le_bytes());
+        assert_eq!(mono, Region::with_data(0x0800_ff00, expect));
+    }
+
+    #[test]
+    fn include_declares_digest() {
+        let region = Region::with_data(0x0800_0000, b"abc".to_vec());
+        let update = FirmwareUpdate::new(region.to_packets(&Default::default(), false));
+        let text = to_include_text(&update, true, &[DigestAlg::Sha256], DEFAULT_SYMBOL_PREFIX);
+        assert!(text.contains(
+            "const uint8_t kBootloaderFirmwareSha256[32] = {\n\t0xba, 0x78, 0x16, 0xbf,"
+        ));
+        assert!(!to_include_text(&update, true, &[], DEFAULT_SYMBOL_PREFIX).contains("Sha256"));
+        assert!(to_include_text(&update, true, &[], "kAppImage").contains("kAppImageBin[] = {"));
+    }
+
+    #[test]
+    fn include_declares_plaintext_crc_when_encrypted() {
+        let region = Region::with_data(0x0800_0000, vec![0x5a; 64]);
+        let packets = region.to_packets(&Default::default(), false);
+        let plain = FirmwareUpdate::new(packets.clone());
+        let mut bytes = plain.to_vec();
+        bytes.extend(plain.crc32().to_le_bytes());
+        assert_eq!(include_crc32(&plain, true), stm32_crc(&bytes));
+
+        // (The packets stand in for the ciphertext)
+        let encrypted = FirmwareUpdate::encrypted(packets, 0x1234_5678);
+        assert_eq!(include_crc32(&encrypted, true), 0x1234_5678);
+        assert_eq!(include_crc32(&encrypted, false), 0x1234_5678);
+    }
 }
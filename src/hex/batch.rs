@@ -0,0 +1,300 @@
+use clap::ValueEnum;
+use std::path::{Path, PathBuf};
+
+use crate::append::{include_crc32, to_binary_file, to_hex_file, to_include_file};
+use crate::digest::DigestAlg;
+use crate::error::Error;
+use crate::metadata::Metadata;
+use crate::output::digest_trailer;
+use crate::patch::{apply_patches, parse_hex_bytes, Endian, Patch};
+use crate::range::{parse_address, parse_number};
+use crate::region::Region;
+use crate::sign::Signer;
+use crate::target::Target;
+
+/**
+ * Output file formats for each unit of a batch.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum BatchFormat {
+    /// Intel HEX file
+    Hex,
+    /// Raw binary file
+    Bin,
+    /// C include file
+    H,
+}
+
+impl BatchFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            BatchFormat::Hex => "hex",
+            BatchFormat::Bin => "bin",
+            BatchFormat::H => "h",
+        }
+    }
+}
+
+/**
+ * Serial number, and other per-unit values, of a single unit.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Unit {
+    serial: u32,
+    patches: Vec<Patch>,
+}
+
+/**
+ * Where, and how, to write the images of a batch.
+ */
+#[derive(Debug, Clone)]
+pub struct BatchOptions {
+    pub dir: PathBuf,
+    pub name: String,
+    pub formats: Vec<BatchFormat>,
+    pub append_crc: bool,
+    pub digests: Vec<DigestAlg>,
+    pub symbol_prefix: String,
+    pub append_digest: bool,
+    pub signer: Option<Signer>,
+    pub extend: bool,
+    pub metadata: Option<Metadata>,
+    /// Start linear address, for the HEX files
+    pub start: Option<u32>,
+}
+
+/**
+ * Manifest entry for one file written by a batch.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ManifestEntry {
+    pub serial: u32,
+    pub file: String,
+    /// CRC32 that the file declares (see 'include_crc32', for a C include file)
+    pub crc32: u32,
+}
+
+/**
+ * Parse a range of serial numbers, as 'FIRST..END' (end-exclusive), or as
+ * 'FIRST+COUNT'.
+ */
+pub fn parse_serials(arg: &str) -> Result<(u32, u32), String> {
+    let (first, end) = if let Some((first, end)) = arg.split_once("..") {
+        (parse_number(first)?, parse_number(end)?)
+    } else if let Some((first, count)) = arg.split_once('+') {
+        let first = parse_number(first)?;
+        let end = first.checked_add(parse_number(count)?);
+        (
+            first,
+            end.ok_or(format!("invalid serial-number range '{}'", arg))?,
+        )
+    } else {
+        return Err(format!(
+            "expected 'FIRST..END' or 'FIRST+COUNT', found '{}'",
+            arg
+        ));
+    };
+    if first >= end || end > 1 << 32 {
+        return Err(format!("invalid serial-number range '{}'", arg));
+    }
+    Ok((first as u32, (end - 1) as u32))
+}
+
+/**
+ * Units for the (inclusive) range of serial numbers, with each serial number
+ * written as a 32-bit value at 'address'.
+ */
+pub fn serial_units(first: u32, last: u32, address: u32, endian: Endian) -> Vec<Unit> {
+    (first..=last)
+        .map(|serial| Unit {
+            serial,
+            patches: vec![Patch::u32(address, serial, endian)],
+        })
+        .collect()
+}
+
+/**
+ * Read units from a CSV file. The first column holds the serial numbers, and
+ * any further columns have an address as their heading, and hex-bytes as the
+ * per-unit values; e.g.
+ * ```text
+ * serial, 0x0801f804
+ * 1001,   DEADBEEF
+ * 1002,   0BADF00D
+ * ```
+ */
+pub fn read_units_csv(path: &Path, address: u32, endian: Endian) -> Result<Vec<Unit>, Error> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| Error::Batch(format!("cannot read '{}' ({})", path.display(), e)))?;
+    let mut lines = text
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty());
+    let err =
+        |num: usize, e: String| Error::Batch(format!("{}:{}: {}", path.display(), num + 1, e));
+
+    let (num, heading) = lines
+        .next()
+        .ok_or(Error::Batch(format!("'{}' is empty", path.display())))?;
+    let columns: Vec<u32> = heading
+        .split(',')
+        .skip(1)
+        .map(|x| parse_address(x).map_err(|e| err(num, e)))
+        .collect::<Result<_, _>>()?;
+
+    let mut units = Vec::new();
+    for (num, line) in lines {
+        let cells: Vec<&str> = line.split(',').map(|x| x.trim()).collect();
+        if cells.len() != columns.len() + 1 {
+            return Err(err(num, format!("expected {} columns", columns.len() + 1)));
+        }
+        let serial = parse_number(cells[0]).map_err(|e| err(num, e))?;
+        let serial =
+            u32::try_from(serial).map_err(|_| err(num, "serial exceeds 32 bits".into()))?;
+        let mut patches = vec![Patch::u32(address, serial, endian)];
+        for (addr, cell) in columns.iter().zip(cells[1..].iter()) {
+            let bytes = parse_hex_bytes(cell).map_err(|e| err(num, e))?;
+            patches.push(Patch::new(*addr, bytes));
+        }
+        units.push(Unit { serial, patches });
+    }
+    Ok(units)
+}
+
+/**
+ * Patch each unit's values into (a copy of) the 'Region's, and then write the
+ * unit's image in each of the chosen formats.
+ */
+pub fn run_batch(
+    regions: &[Region],
+    units: &[Unit],
+    target: &Target,
+    options: &BatchOptions,
+) -> Result<Vec<ManifestEntry>, Error> {
+    std::fs::create_dir_all(&options.dir)
+        .map_err(|e| Error::Batch(format!("cannot create '{}' ({})", options.dir.display(), e)))?;
+    let mut manifest = Vec::new();
+
+    for u in units.iter() {
+        let mut unit = regions.to_vec();
+        apply_patches(&mut unit, &u.patches, options.extend)?;
+        if let Some(metadata) = &options.metadata {
+            metadata.apply(&mut unit, target, options.append_crc)?;
+        }
+        let embedded = match &options.signer {
+            Some(signer) => signer.embed(&mut unit, target, options.append_crc)?,
+            None => None,
+        };
+        let update = crate::make_update(&unit, target, options.append_crc);
+        let mut trailer = match options.append_digest {
+            true => digest_trailer(&update, &options.digests),
+            false => Vec::new(),
+        };
+        if let (Some(signer), None) = (&options.signer, embedded) {
+            trailer.extend(signer.sign(&update.to_vec()));
+        }
+        let name = options.name.replace("{serial}", &u.serial.to_string());
+
+        for format in options.formats.iter() {
+            let file = format!("{}.{}", name, format.extension());
+            let path = options.dir.join(&file);
+            let path = path.to_str().unwrap();
+            // The CRC32 that the file declares
+            let crc32 = match format {
+                BatchFormat::Hex => {
+                    to_hex_file(path, &update, options.append_crc, options.start);
+                    update.crc32()
+                }
+                BatchFormat::Bin => {
+                    to_binary_file(path, &update, options.append_crc, &trailer, target);
+                    update.crc32()
+                }
+                BatchFormat::H => {
+                    to_include_file(
+                        path,
+                        &update,
+                        options.append_crc,
+                        &options.digests,
+                        &options.symbol_prefix,
+                    );
+                    include_crc32(&update, options.append_crc)
+                }
+            };
+            manifest.push(ManifestEntry {
+                serial: u.serial,
+                file,
+                crc32,
+            });
+        }
+    }
+    Ok(manifest)
+}
+
+pub fn to_manifest_text(manifest: &[ManifestEntry]) -> String {
+    let mut text = "serial,file,crc32\n".to_string();
+    for m in manifest.iter() {
+        text.push_str(&format!("{},{},0x{:08X}\n", m.serial, m.file, m.crc32));
+    }
+    text
+}
+
+//----------------------------------------------------------------------------
+// Tests
+//----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_serials, run_batch, serial_units, BatchFormat, BatchOptions};
+    use crate::header::parse_include_text;
+    use crate::patch::{apply_patches, Endian};
+    use crate::region::Region;
+
+    #[test]
+    fn serial_ranges() {
+        assert_eq!(parse_serials("1000..1100"), Ok((1000, 1099)));
+        assert_eq!(parse_serials("1000+3"), Ok((1000, 1002)));
+        assert!(parse_serials("1000..1000").is_err());
+        assert!(parse_serials("1000").is_err());
+        assert!(parse_serials("0xffffffffffffffff+1").is_err());
+    }
+
+    #[test]
+    fn units_have_unique_crcs() {
+        let regions = vec![Region::with_data(0x0800_0000, vec![0xff; 64])];
+        let units = serial_units(7, 9, 0x0800_0010, Endian::Little);
+        assert_eq!(units.len(), 3);
+
+        let mut crcs = Vec::new();
+        for u in units.iter() {
+            let mut unit = regions.clone();
+            apply_patches(&mut unit, &u.patches, false).unwrap();
+            crcs.push(crate::make_update(&unit, &Default::default(), false).crc32());
+        }
+        crcs.dedup();
+        assert_eq!(crcs.len(), 3);
+    }
+
+    #[test]
+    fn manifest_has_declared_crcs() {
+        let regions = vec![Region::with_data(0x0800_0000, vec![0xff; 64])];
+        let units = serial_units(7, 7, 0x0800_0010, Endian::Little);
+        let options = BatchOptions {
+            dir: std::env::temp_dir().join("ihex-crc32-batch-test"),
+            name: "unit-{serial}".to_string(),
+            formats: vec![BatchFormat::Bin, BatchFormat::H],
+            append_crc: true,
+            digests: Vec::new(),
+            symbol_prefix: "kApp".to_string(),
+            append_digest: false,
+            signer: None,
+            extend: false,
+            metadata: None,
+            start: None,
+        };
+        let manifest = run_batch(&regions, &units, &Default::default(), &options).unwrap();
+        let text = std::fs::read_to_string(options.dir.join("unit-7.h")).unwrap();
+        let header = parse_include_text(&text, "kApp").unwrap();
+        assert_eq!(manifest[1].crc32, header.crc32);
+        assert_ne!(manifest[0].crc32, manifest[1].crc32);
+    }
+}
@@ -0,0 +1,137 @@
+use clap::ValueEnum;
+
+use crate::error::Error;
+use crate::hexcrc::stm32_crc;
+use crate::packet::FirmwareUpdatePacket;
+use crate::region::Region;
+use crate::target::Target;
+
+/**
+ * Compression formats, for the firmware payload. (Heatshrink is not
+ * supported, as its window and lookahead sizes are fixed when the decoder is
+ * built, so are not something the header could describe.)
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Compression {
+    /// LZ4 block format (no frame header)
+    Lz4,
+    /// Raw DEFLATE stream (no zlib header), e.g. for 'uzlib', or 'tinf'
+    Deflate,
+}
+
+impl Compression {
+    /// Format identifier, within the header packet.
+    pub fn id(&self) -> u8 {
+        match self {
+            Compression::Lz4 => 1,
+            Compression::Deflate => 2,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Compression::Lz4 => "LZ4",
+            Compression::Deflate => "DEFLATE",
+        }
+    }
+
+    pub fn compress(&self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Compression::Lz4 => lz4_flex::block::compress(data),
+            Compression::Deflate => miniz_oxide::deflate::compress_to_vec(data, 10),
+        }
+    }
+
+    /**
+     * Reference decompressor, matching what the bootloader must implement.
+     */
+    pub fn decompress(&self, data: &[u8], length: usize) -> Result<Vec<u8>, Error> {
+        let bytes = match self {
+            Compression::Lz4 => lz4_flex::block::decompress(data, length)
+                .map_err(|e| Error::Compression(e.to_string()))?,
+            Compression::Deflate => miniz_oxide::inflate::decompress_to_vec(data)
+                .map_err(|e| Error::Compression(format!("{:?}", e.status)))?,
+        };
+        match bytes.len() == length {
+            true => Ok(bytes),
+            false => Err(Error::Compression(format!(
+                "expected {} bytes, found {}",
+                length,
+                bytes.len()
+            ))),
+        }
+    }
+}
+
+/**
+ * Compress the (contiguous) image, and packetise the compressed stream. The
+ * stream is preceded by a header packet (a '*z' packet, addressed to the base
+ * of the image) that holds the format, the length and CRC32 of the image, and
+ * the length of the compressed stream:
+ * ```text
+ * | format (u8) | 0 | 0 | 0 | length (u32) | crc32 (u32) | compressed length (u32) |
+ * ```
+ * with all values little-endian. The stream follows in compressed-data ('*c')
+ * packets, each addressed by its offset within the stream.
+ */
+pub fn compressed_packets(
+    image: &Region,
+    format: Compression,
+    target: &Target,
+) -> Vec<FirmwareUpdatePacket> {
+    let stream = format.compress(image.data());
+    let header = FirmwareUpdatePacket::compression_header(
+        image.address(),
+        format.id(),
+        image.len() as u32,
+        stm32_crc(image.data()),
+        stream.len() as u32,
+    );
+    let mut packets = vec![header];
+    packets.extend(
+        Region::with_data(0, stream)
+            .to_packets(target, false)
+            .into_iter()
+            .map(FirmwareUpdatePacket::into_compressed),
+    );
+    packets
+}
+
+//----------------------------------------------------------------------------
+// Tests
+//----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::{compressed_packets, Compression};
+    use crate::hexcrc::stm32_crc;
+    use crate::region::Region;
+
+    #[test]
+    fn decompress_packets() {
+        // Compressible, but not trivially so
+        let data: Vec<u8> = (0..5000u32).map(|x| (x * x / 7) as u8).collect();
+        let region = Region::with_data(0x0800_c800, data);
+        let target = Default::default();
+
+        for format in [Compression::Lz4, Compression::Deflate] {
+            let packets = compressed_packets(&region, format, &target);
+            let header = packets[0].to_vec();
+            assert_eq!(packets[0].to_bytes()[1], b'z');
+            assert_eq!(packets[0].address(), region.address());
+            assert_eq!(header[0], format.id());
+            let word = |i: usize| u32::from_le_bytes(header[i..i + 4].try_into().unwrap());
+            let (length, crc32, size) = (word(4) as usize, word(8), word(12) as usize);
+            assert_eq!(crc32, stm32_crc(region.data()));
+
+            let stream: Vec<u8> = packets[1..].iter().flat_map(|p| p.to_vec()).collect();
+            assert_eq!(stream.len(), size);
+            assert!(packets[1..].iter().all(|p| p.to_bytes()[1] == b'c'));
+            assert_eq!(packets[1].address(), 0);
+            assert_eq!(packets[2].address() as usize, packets[1].len());
+            assert!(size < length);
+            let bytes = format.decompress(&stream, length).unwrap();
+            assert_eq!(Region::with_data(packets[0].address(), bytes), region);
+        }
+    }
+}
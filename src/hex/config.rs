@@ -0,0 +1,184 @@
+use serde::Deserialize;
+use std::path::Path;
+
+use crate::device::Device;
+use crate::error::Error;
+use crate::output::split_path;
+
+/// Config file that is read from the current directory, if there is no
+/// '--config' option.
+pub const CONFIG_FILE: &str = "ihex-crc32.toml";
+
+/**
+ * Output files, with paths relative to the config file.
+ */
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigOutputs {
+    pub include: Option<String>,
+    pub binary: Option<String>,
+    pub hex: Option<String>,
+    pub packets: Option<String>,
+}
+
+/**
+ * Project settings, so that each firmware repository can check in how its
+ * images are built, e.g.:
+ * ```toml
+ * input = "build/app.hex"
+ * granularity = "stm32g4"
+ * append_crc = true
+ * set_u32 = ["0x0801f800=1234"]
+ * symbol_prefix = "kAppFirmware"
+ *
+ * [outputs]
+ * include = "Inc/app_fw_image.h"
+ * binary = "build/app.bin"
+ * ```
+ * Values use the same syntax as the command-line options, which override
+ * them.
+ */
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub input: Option<String>,
+    /// CRC32 algorithm, which must be 'stm32' (the only one supported)
+    pub crc: Option<String>,
+    /// Value of the fill (and padding) bytes, which must be 0
+    pub fill: Option<u8>,
+    pub granularity: Option<String>,
+    pub device: Option<String>,
+    pub append_crc: Option<bool>,
+    pub overlap: Option<String>,
+    pub include_range: Option<Vec<String>>,
+    pub exclude_range: Option<Vec<String>>,
+    pub offset: Option<String>,
+    pub relocate: Option<String>,
+    pub set: Option<Vec<String>>,
+    pub set_u32: Option<Vec<String>>,
+    pub patch_file: Option<String>,
+    pub endian: Option<String>,
+    pub extend: Option<bool>,
+    pub digest: Option<Vec<String>>,
+    pub symbol_prefix: Option<String>,
+    /// Further outputs, as for '--output' (e.g. "binary:build/app.bin,nocrc")
+    pub output: Option<Vec<String>>,
+    #[serde(default)]
+    pub outputs: ConfigOutputs,
+}
+
+impl Config {
+    /**
+     * Read the config file, and make its (relative) input, patch-file,
+     * device-profile, and output paths (including those of 'output' specs)
+     * relative to the directory of the config file.
+     */
+    pub fn from_file(path: &Path) -> Result<Self, Error> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| Error::Config(format!("cannot read '{}' ({})", path.display(), e)))?;
+        let mut config = Self::from_toml(&text).map_err(|e| match e {
+            Error::Config(msg) => Error::Config(format!("'{}': {}", path.display(), msg)),
+            e => e,
+        })?;
+
+        let dir = path.parent().unwrap_or(Path::new(""));
+        let resolve = |x: &mut Option<String>| {
+            if let Some(p) = x
+                .as_mut()
+                .filter(|p| *p != "-" && Path::new(p.as_str()).is_relative())
+            {
+                *p = dir.join(p.as_str()).to_string_lossy().into_owned();
+            }
+        };
+        resolve(&mut config.input);
+        resolve(&mut config.patch_file);
+        // A device profile may be a built-in name, rather than a file
+        if config.device.as_deref().and_then(Device::builtin).is_none() {
+            resolve(&mut config.device);
+        }
+        resolve(&mut config.outputs.include);
+        resolve(&mut config.outputs.binary);
+        resolve(&mut config.outputs.hex);
+        resolve(&mut config.outputs.packets);
+        for x in config.output.iter_mut().flatten() {
+            if let Some((format, path, options)) = split_path(x) {
+                let mut path = Some(path.to_string());
+                resolve(&mut path);
+                *x = format!("{}{}{}", format, path.unwrap_or_default(), options);
+            }
+        }
+        Ok(config)
+    }
+
+    pub fn from_toml(text: &str) -> Result<Self, Error> {
+        let config: Self = toml::from_str(text).map_err(|e| Error::Config(format!("{}", e)))?;
+        if let Some(crc) = config
+            .crc
+            .as_ref()
+            .filter(|x| !x.eq_ignore_ascii_case("stm32"))
+        {
+            return Err(Error::Config(format!(
+                "unsupported CRC algorithm '{}' (expected 'stm32')",
+                crc
+            )));
+        }
+        if let Some(fill) = config.fill.filter(|x| *x != 0) {
+            return Err(Error::Config(format!(
+                "unsupported fill value 0x{:02x} (gaps and padding are zero-filled)",
+                fill
+            )));
+        }
+        Ok(config)
+    }
+}
+
+//----------------------------------------------------------------------------
+// Tests
+//----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::Config;
+    use std::path::Path;
+
+    #[test]
+    fn parse_config() {
+        let text = r#"
+            input = "build/app.hex"
+            append_crc = true
+            set_u32 = ["0x0801f800=1234"]
+
+            [outputs]
+            include = "Inc/app.h"
+        "#;
+        let config = Config::from_toml(text).unwrap();
+        assert_eq!(config.append_crc, Some(true));
+        assert_eq!(config.outputs.include.as_deref(), Some("Inc/app.h"));
+        assert!(config.granularity.is_none());
+
+        assert!(Config::from_toml("crc = \"crc32c\"").is_err());
+        assert!(Config::from_toml("fill = 0xff").is_err());
+        assert!(Config::from_toml("binary = \"app.bin\"").is_err());
+    }
+
+    #[test]
+    fn paths_relative_to_config() {
+        let dir = std::env::temp_dir().join("ihex-crc32-config-test");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("ihex-crc32.toml");
+        let text =
+            "input = \"app.hex\"\noutput = [\"hex:out/app.hex,crc\"]\n[outputs]\nbinary = \"-\"\n";
+        std::fs::write(&path, text).unwrap();
+        let config = Config::from_file(&path).unwrap();
+        assert_eq!(
+            config.input.map(|x| Path::new(&x).to_path_buf()),
+            Some(dir.join("app.hex"))
+        );
+        assert_eq!(config.outputs.binary.as_deref(), Some("-"));
+        let output = dir.join("out").join("app.hex");
+        assert_eq!(
+            config.output,
+            Some(vec![format!("hex:{},crc", output.display())])
+        );
+    }
+}
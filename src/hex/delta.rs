@@ -0,0 +1,379 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use crate::device::Device;
+use crate::erase::ErasePlan;
+use crate::error::Error;
+use crate::packet::FirmwareUpdatePacket;
+use crate::range::{parse_address, parse_number, AddressRange};
+use crate::region::Region;
+use crate::target::Target;
+use crate::update::FirmwareUpdate;
+
+/// Flash page size, when there is no device profile.
+pub const DEFAULT_PAGE_SIZE: u32 = 0x800;
+
+/**
+ * Flash page that is (re-)written by a delta update.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Page {
+    pub address: u32,
+    pub size: u32,
+}
+
+impl Page {
+    pub fn range(&self) -> Option<AddressRange> {
+        AddressRange::with_size(self.address, self.size)
+    }
+}
+
+/**
+ * Parse a flash page size, which must be a power of two.
+ */
+pub fn parse_page_size(arg: &str) -> Result<u32, String> {
+    match parse_address(arg)? {
+        size if size.is_power_of_two() => Ok(size),
+        _ => Err(format!("page size must be a power of two, found '{}'", arg)),
+    }
+}
+
+/**
+ * Flash pages that overlap the range, using the sectors of the device (if
+ * given), or else pages of 'page_size' bytes.
+ */
+pub fn pages(range: &AddressRange, device: Option<&Device>, page_size: u32) -> Vec<Page> {
+    if let Some(device) = device {
+        return device
+            .sectors(range)
+            .iter()
+            .map(|s| Page {
+                address: s.address,
+                size: s.size,
+            })
+            .collect();
+    }
+    let mut pages = Vec::new();
+    let mut address = range.start() - range.start() % page_size;
+    while address < range.end() {
+        pages.push(Page {
+            address,
+            size: page_size,
+        });
+        address = match address.checked_add(page_size) {
+            Some(next) => next,
+            None => break,
+        };
+    }
+    pages
+}
+
+/**
+ * Split the image at page boundaries, and packetise each page on its own, so
+ * that no packet straddles two pages.
+ */
+pub fn page_packets(
+    image: &Region,
+    pages: &[Page],
+    target: &Target,
+) -> Vec<(Page, Vec<FirmwareUpdatePacket>)> {
+    pages
+        .iter()
+        .filter_map(|page| {
+            let r = image.clip(&page.range()?)?;
+            Some((*page, r.to_packets(target, false)))
+        })
+        .collect()
+}
+
+/**
+ * What is known about the image that is currently installed on the device.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Installed {
+    /// Installed image, e.g. from the HEX file of the previous release
+    Image(Region),
+    /// CRC16 of each (page-aligned) packet, by packet address, as reported by
+    /// the device
+    Crc16s(BTreeMap<u32, u16>),
+}
+
+impl Installed {
+    fn unchanged(&self, packet: &FirmwareUpdatePacket) -> bool {
+        match self {
+            Installed::Image(image) => {
+                let range = AddressRange::with_size(packet.address(), packet.len() as u32);
+                range.and_then(|x| image.clip(&x))
+                    == Some(Region::with_data(packet.address(), packet.to_vec()))
+            }
+            Installed::Crc16s(crcs) => crcs.get(&packet.address()) == Some(&packet.crc16()),
+        }
+    }
+}
+
+/**
+ * Read a device-reported list of packet CRC16s, with one 'ADDR CRC16' (or
+ * 'ADDR,CRC16') pair per line, and '#' comments.
+ */
+pub fn read_crc16_list(path: &Path) -> Result<BTreeMap<u32, u16>, Error> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| Error::Input(format!("cannot read '{}' ({})", path.display(), e)))?;
+    let mut crcs = BTreeMap::new();
+    for (num, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let err = |e: String| Error::Input(format!("{}:{}: {}", path.display(), num + 1, e));
+        let fields: Vec<&str> = line
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|x| !x.is_empty())
+            .collect();
+        let (addr, crc) = match fields[..] {
+            [addr, crc] => (addr, crc),
+            _ => return Err(err(format!("expected 'ADDR CRC16', found '{}'", line))),
+        };
+        let crc = parse_number(crc).map_err(err)?;
+        let crc = u16::try_from(crc).map_err(|_| err(format!("'{}' exceeds 16 bits", line)))?;
+        crcs.insert(parse_address(addr).map_err(err)?, crc);
+    }
+    Ok(crcs)
+}
+
+/**
+ * Page-aligned packets of the new image, for just those pages that hold at
+ * least one packet that differs from the installed image (as the whole page
+ * is erased before it is programmed). Returns the packets, and the pages that
+ * they cover.
+ */
+pub fn changed_packets(
+    update: &FirmwareUpdate,
+    installed: &Installed,
+    pages: &[Page],
+    target: &Target,
+) -> (Vec<FirmwareUpdatePacket>, Vec<Page>) {
+    let image = match update.to_region() {
+        Some(image) => image,
+        None => return (Vec::new(), Vec::new()),
+    };
+    let mut packets = Vec::new();
+    let mut changed = Vec::new();
+    for (page, mut chunk) in page_packets(&image, pages, target) {
+        if !chunk.iter().all(|p| installed.unchanged(p)) {
+            changed.push(page);
+            packets.append(&mut chunk);
+        }
+    }
+    (packets, changed)
+}
+
+/**
+ * Grow the changed pages to cover every page of each sector that the erase
+ * plan will erase, as erasing a sector blanks all of its pages (and the
+ * sectors may be larger than the pages). Returns the pages, in order.
+ */
+pub fn erased_pages(pages: &[Page], changed: &[Page], plan: &ErasePlan) -> Vec<Page> {
+    let mut kept = changed.to_vec();
+    loop {
+        let mut erase = plan.clone();
+        erase.retain_overlapping(&kept.iter().filter_map(|p| p.range()).collect::<Vec<_>>());
+        let erased = erase.ranges();
+        let grown: Vec<Page> = pages
+            .iter()
+            .filter(|p| {
+                p.range()
+                    .is_some_and(|x| erased.iter().any(|r| r.overlaps(&x)))
+            })
+            .copied()
+            .collect();
+        if grown.len() <= kept.len() {
+            return kept;
+        }
+        kept = grown;
+    }
+}
+
+/**
+ * Delta update: just the flash pages whose contents differ between the old
+ * (installed) image and the new image, along with the length and CRC32 of the
+ * complete new image, so that the device can verify it after the update.
+ */
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Delta {
+    pages: Vec<Page>,
+    page_count: usize,
+    length: usize,
+    crc32: u32,
+    #[serde(skip)]
+    packets: Vec<FirmwareUpdatePacket>,
+}
+
+impl Delta {
+    /**
+     * Compare the new firmware update against the old image, page by page.
+     * The packets of each changed page hold the same bytes as the complete
+     * update does, for that page.
+     */
+    pub fn new(
+        old: &FirmwareUpdate,
+        new: &FirmwareUpdate,
+        device: Option<&Device>,
+        page_size: u32,
+        target: &Target,
+    ) -> Self {
+        let old = old.to_region();
+        let image = new.to_region().unwrap_or_else(|| Region::new(0));
+        let all = match image.is_empty() {
+            true => Vec::new(),
+            false => pages(&image.range(), device, page_size),
+        };
+
+        let mut pages = Vec::new();
+        let mut packets = Vec::new();
+        for (page, mut chunk) in page_packets(&image, &all, target) {
+            let range = page.range();
+            let clip = |r: &Region| range.and_then(|x| r.clip(&x));
+            if clip(&image) != old.as_ref().and_then(clip) {
+                pages.push(page);
+                packets.append(&mut chunk);
+            }
+        }
+        Self {
+            pages,
+            page_count: all.len(),
+            length: new.len(),
+            crc32: new.crc32(),
+            packets,
+        }
+    }
+
+    /// Changed pages.
+    pub fn pages(&self) -> &[Page] {
+        &self.pages
+    }
+
+    /// Number of pages covered by the new image.
+    pub fn page_count(&self) -> usize {
+        self.page_count
+    }
+
+    /// Length of the complete new image.
+    pub fn len(&self) -> usize {
+        self.length
+    }
+
+    /// CRC32 of the complete new image.
+    pub fn crc32(&self) -> u32 {
+        self.crc32
+    }
+
+    pub fn packets(&self) -> &[FirmwareUpdatePacket] {
+        &self.packets
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+}
+
+//----------------------------------------------------------------------------
+// Tests
+//----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::{changed_packets, erased_pages, page_packets, pages, Delta, Installed, Page};
+    use crate::device::Device;
+    use crate::erase::ErasePlan;
+    use crate::region::Region;
+
+    #[test]
+    fn only_changed_pages() {
+        let target = Default::default();
+        let old: Vec<u8> = (0..0x1000).map(|x| x as u8).collect();
+        let mut new = old.clone();
+        new[0x0a10] ^= 0xff;
+        new.extend([0xaa; 0x10]);
+        let old = crate::make_update(&[Region::with_data(0x0800_0000, old)], &target, false);
+        let new = crate::make_update(&[Region::with_data(0x0800_0000, new)], &target, false);
+
+        let delta = Delta::new(&old, &new, None, 0x800, &target);
+        assert_eq!(delta.page_count(), 3);
+        assert_eq!(
+            delta.pages(),
+            [
+                Page {
+                    address: 0x0800_0800,
+                    size: 0x800
+                },
+                Page {
+                    address: 0x0800_1000,
+                    size: 0x800
+                }
+            ]
+        );
+        assert_eq!(delta.crc32(), new.crc32());
+
+        // Packets hold the new contents of the changed pages
+        let data: Vec<u8> = delta.packets().iter().flat_map(|p| p.to_vec()).collect();
+        assert_eq!(data, new.to_vec()[0x800..]);
+        assert_eq!(delta.packets()[0].address(), 0x0800_0800);
+    }
+
+    #[test]
+    fn skip_unchanged_packets() {
+        let target = Default::default();
+        let old: Vec<u8> = (0..0x1800).map(|x| x as u8).collect();
+        let mut new = old.clone();
+        new[0x1000] ^= 0xff;
+        let old = Region::with_data(0x0800_0000, old);
+        let new = crate::make_update(&[Region::with_data(0x0800_0000, new)], &target, false);
+        let pages = pages(&new.to_region().unwrap().range(), None, 0x800);
+
+        let expect = [Page {
+            address: 0x0800_1000,
+            size: 0x800,
+        }];
+        let image = Installed::Image(old.clone());
+        let (packets, changed) = changed_packets(&new, &image, &pages, &target);
+        assert_eq!(changed, expect);
+        let data: Vec<u8> = packets.iter().flat_map(|p| p.to_vec()).collect();
+        assert_eq!(data, new.to_vec()[0x1000..]);
+
+        // The same, from the CRC16s of the unchanged packets
+        let all = page_packets(&old, &pages, &target);
+        let crcs = all
+            .iter()
+            .flat_map(|(_, ps)| ps.iter().map(|p| (p.address(), p.crc16())))
+            .collect();
+        let (_, changed) = changed_packets(&new, &Installed::Crc16s(crcs), &pages, &target);
+        assert_eq!(changed, expect);
+    }
+
+    #[test]
+    fn erase_every_page_of_a_sector() {
+        let text =
+            "name = \"x\"\n[[banks]]\nbase = 0x08000000\nsize = 0x10000\nsector_size = 0x4000\n";
+        let device: Device = toml::from_str(text).unwrap();
+        let target = Default::default();
+        let old: Vec<u8> = (0..0x8000).map(|x| x as u8).collect();
+        let mut new = old.clone();
+        new[0x4a10] ^= 0xff;
+        let old = Region::with_data(0x0800_0000, old);
+        let new = crate::make_update(&[Region::with_data(0x0800_0000, new)], &target, false);
+        let image = new.to_region().unwrap();
+        let pages = pages(&image.range(), None, 0x800);
+        let (_, changed) = changed_packets(&new, &Installed::Image(old), &pages, &target);
+        assert_eq!(changed.len(), 1);
+
+        // The 16 kB sector that holds the changed 2 kB page is erased, so all
+        // eight of its pages must be re-programmed
+        let plan = ErasePlan::new(&device, &[image]).unwrap();
+        let kept = erased_pages(&pages, &changed, &plan);
+        assert_eq!(kept.len(), 8);
+        assert_eq!(kept[0].address, 0x0800_4000);
+        assert_eq!(kept[7].address, 0x0800_7800);
+        assert_eq!(erased_pages(&pages, &[], &plan), []);
+    }
+}
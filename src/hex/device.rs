@@ -0,0 +1,335 @@
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+use crate::error::Error;
+use crate::range::{deserialize_u32, AddressRange};
+use crate::region::Region;
+use crate::target::Target;
+
+/**
+ * Contiguous bank of flash memory, made up of equally-sized sectors (pages).
+ */
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct FlashBank {
+    #[serde(deserialize_with = "deserialize_u32")]
+    base: u32,
+    #[serde(deserialize_with = "deserialize_u32")]
+    size: u32,
+    #[serde(deserialize_with = "deserialize_u32")]
+    sector_size: u32,
+}
+
+/**
+ * Area of flash that firmware updates must never write to; e.g., the
+ * bootloader itself, or per-unit calibration data.
+ */
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct ReservedArea {
+    name: String,
+    #[serde(deserialize_with = "deserialize_u32")]
+    start: u32,
+    #[serde(deserialize_with = "deserialize_u32")]
+    size: u32,
+}
+
+/**
+ * Flash sector (or page) that is touched by a firmware update.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub struct Sector {
+    pub bank: usize,
+    pub index: u32,
+    pub address: u32,
+    pub size: u32,
+}
+
+/**
+ * Flash memory map of an MCU, for validating the addresses of firmware data.
+ */
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Device {
+    name: String,
+    #[serde(default = "default_granularity")]
+    granularity: usize,
+    banks: Vec<FlashBank>,
+    #[serde(default)]
+    reserved: Vec<ReservedArea>,
+}
+
+fn default_granularity() -> usize {
+    Target::STM32G4.granularity()
+}
+
+const FLASH_BASE: u32 = 0x0800_0000;
+const STM32G4_PAGE: u32 = 0x800;
+
+/// Built-in device profiles, for STM32G4xx parts in their default (flash) configurations.
+const BUILTIN_NAMES: [&str; 3] = ["stm32g431xb", "stm32g474xe", "stm32g491xe"];
+
+impl FlashBank {
+    pub fn new(base: u32, size: u32, sector_size: u32) -> Self {
+        Self {
+            base,
+            size,
+            sector_size,
+        }
+    }
+
+    pub fn range(&self) -> Option<AddressRange> {
+        AddressRange::with_size(self.base, self.size)
+    }
+}
+
+impl Sector {
+    pub fn range(&self) -> Option<AddressRange> {
+        AddressRange::with_size(self.address, self.size)
+    }
+}
+
+impl Device {
+    pub fn builtin(name: &str) -> Option<Device> {
+        let banks = match name.to_ascii_lowercase().as_str() {
+            "stm32g431xb" => vec![FlashBank::new(FLASH_BASE, 0x2_0000, STM32G4_PAGE)],
+            // Dual-bank mode ('DBANK = 1') is the factory default
+            "stm32g474xe" => vec![
+                FlashBank::new(FLASH_BASE, 0x4_0000, STM32G4_PAGE),
+                FlashBank::new(FLASH_BASE + 0x4_0000, 0x4_0000, STM32G4_PAGE),
+            ],
+            "stm32g491xe" => vec![FlashBank::new(FLASH_BASE, 0x8_0000, STM32G4_PAGE)],
+            _ => return None,
+        };
+        Some(Self {
+            name: name.to_ascii_lowercase(),
+            granularity: Target::STM32G4.granularity(),
+            banks,
+            reserved: Vec::new(),
+        })
+    }
+
+    pub fn builtin_names() -> &'static [&'static str] {
+        &BUILTIN_NAMES
+    }
+
+    /**
+     * Read a device profile from a JSON ('.json' extension) or TOML file.
+     */
+    pub fn from_file(path: &Path) -> Result<Device, Error> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| Error::Device(format!("cannot read '{}' ({})", path.display(), e)))?;
+        let device: Device = if path.extension().is_some_and(|x| x == "json") {
+            serde_json::from_str(&text).map_err(|e| Error::Device(format!("{}", e)))?
+        } else {
+            toml::from_str(&text).map_err(|e| Error::Device(format!("{}", e)))?
+        };
+        device.check()?;
+        Ok(device)
+    }
+
+    /**
+     * Look up a built-in device profile, else read the profile from a file.
+     */
+    pub fn load(arg: &str) -> Result<Device, Error> {
+        match Device::builtin(arg) {
+            Some(device) => Ok(device),
+            None if Path::new(arg).exists() => Device::from_file(Path::new(arg)),
+            None => Err(Error::Device(format!(
+                "unknown device '{}' (built-in devices: {})",
+                arg,
+                Device::builtin_names().join(", ")
+            ))),
+        }
+    }
+
+    fn check(&self) -> Result<(), Error> {
+        if Target::new(self.granularity).is_none() {
+            return Err(Error::Device(format!(
+                "invalid program granularity ({})",
+                self.granularity
+            )));
+        }
+        if self.banks.is_empty() {
+            return Err(Error::Device("no flash banks".to_string()));
+        }
+        // Each bank, and reserved area, must be representable as an
+        // 'AddressRange' (i.e. end at, or below, 0xffffffff)
+        for b in self.banks.iter() {
+            if b.sector_size == 0 || b.size % b.sector_size != 0 || b.range().is_none() {
+                return Err(Error::Device(format!(
+                    "invalid flash bank at 0x{:08x} (size = {}, sector size = {})",
+                    b.base, b.size, b.sector_size
+                )));
+            }
+        }
+        for r in self.reserved.iter() {
+            if AddressRange::with_size(r.start, r.size).is_none() {
+                return Err(Error::Device(format!(
+                    "invalid reserved area '{}' at 0x{:08x} (size = {})",
+                    r.name, r.start, r.size
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn target(&self) -> Target {
+        Target::new(self.granularity).unwrap()
+    }
+
+    /**
+     * All sectors that overlap the given address range.
+     */
+    pub fn sectors(&self, range: &AddressRange) -> Vec<Sector> {
+        let mut sectors = Vec::new();
+        for (bank, b) in self.banks.iter().enumerate() {
+            if let Some(r) = b.range().and_then(|x| x.intersect(range)) {
+                let first = (r.start() - b.base) / b.sector_size;
+                let last = (r.end() - 1 - b.base) / b.sector_size;
+                for index in first..=last {
+                    sectors.push(Sector {
+                        bank,
+                        index,
+                        address: b.base + index * b.sector_size,
+                        size: b.sector_size,
+                    });
+                }
+            }
+        }
+        sectors
+    }
+
+    /**
+     * Check that every address of the range lies within a flash bank, and
+     * outside of all reserved areas.
+     */
+    pub fn check_range(&self, range: &AddressRange) -> Result<(), Error> {
+        let mut addr = range.start();
+        while addr < range.end() {
+            let mut banks = self.banks.iter().filter_map(|b| b.range());
+            match banks.find(|b| b.contains(addr)) {
+                Some(b) => addr = b.end().min(range.end()),
+                None => {
+                    return Err(Error::NotInFlash {
+                        device: self.name.clone(),
+                        address: addr,
+                        size: (range.end() - addr) as usize,
+                    })
+                }
+            }
+        }
+        for r in self.reserved.iter() {
+            let area = AddressRange::with_size(r.start, r.size);
+            if let Some(x) = area.and_then(|a| a.intersect(range)) {
+                return Err(Error::Reserved {
+                    name: r.name.clone(),
+                    address: x.start(),
+                    size: x.len(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /**
+     * Validate that all 'Region's lie inside writable flash, and return the
+     * (sorted) list of sectors that the update touches.
+     */
+    pub fn validate(&self, regions: &[Region]) -> Result<Vec<Sector>, Error> {
+        let mut sectors = Vec::new();
+        for r in regions.iter().filter(|r| !r.is_empty()) {
+            let range = r.range();
+            self.check_range(&range)?;
+            sectors.append(&mut self.sectors(&range));
+        }
+        sectors.sort();
+        sectors.dedup();
+        Ok(sectors)
+    }
+}
+
+//----------------------------------------------------------------------------
+// Tests
+//----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::Device;
+    use crate::error::Error;
+    use crate::range::AddressRange;
+    use std::path::Path;
+
+    #[test]
+    fn builtin_profiles_are_valid() {
+        for name in Device::builtin_names() {
+            let device = Device::builtin(name).unwrap();
+            assert!(device.check().is_ok());
+        }
+        assert!(Device::builtin("stm32f407vg").is_none());
+    }
+
+    #[test]
+    fn reject_ranges_past_4gb() {
+        let bank = "[[banks]]\nbase = 0xffff0000\nsize = 0x10000\nsector_size = 0x800\n";
+        let device: Device = toml::from_str(&format!("name = \"x\"\n{}", bank)).unwrap();
+        assert!(matches!(device.check(), Err(Error::Device(_))));
+
+        let text =
+            "name = \"x\"\n[[banks]]\nbase = 0x08000000\nsize = 0x800\nsector_size = 0x800\n\
+                    [[reserved]]\nname = \"r\"\nstart = 0xfffffff0\nsize = 0x100\n";
+        let device: Device = toml::from_str(text).unwrap();
+        assert!(matches!(device.check(), Err(Error::Device(_))));
+    }
+
+    #[test]
+    fn sectors_across_banks() {
+        let device = Device::builtin("stm32g474xe").unwrap();
+        let range = AddressRange::new(0x0803_f000, 0x0804_0801);
+        let sectors = device.sectors(&range);
+        assert!(device.check_range(&range).is_ok());
+        assert_eq!(sectors.len(), 4);
+        assert_eq!(sectors[1].bank, 0);
+        assert_eq!(sectors[1].index, 127);
+        assert_eq!(sectors[2].bank, 1);
+        assert_eq!(sectors[2].address, 0x0804_0000);
+        assert_eq!(sectors[3].index, 1);
+    }
+
+    #[test]
+    fn reject_data_outside_flash() {
+        let device = Device::builtin("stm32g431xb").unwrap();
+        let ram = AddressRange::new(0x2000_0000, 0x2000_0100);
+        let end = AddressRange::new(0x0801_ff00, 0x0802_0100);
+        assert!(matches!(
+            device.check_range(&ram),
+            Err(Error::NotInFlash {
+                address: 0x2000_0000,
+                ..
+            })
+        ));
+        assert!(matches!(
+            device.check_range(&end),
+            Err(Error::NotInFlash {
+                address: 0x0802_0000,
+                size: 0x100,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn example_profile_has_reserved_areas() {
+        let device = Device::from_file(Path::new("data/device.toml")).unwrap();
+        let boot = AddressRange::new(0x0800_c000, 0x0800_c900);
+        assert!(matches!(
+            device.check_range(&boot),
+            Err(Error::Reserved {
+                address: 0x0800_c000,
+                size: 0x800,
+                ..
+            })
+        ));
+    }
+}
@@ -0,0 +1,202 @@
+use crate::packet::FirmwareUpdatePacket;
+use crate::range::AddressRange;
+use crate::region::Region;
+
+/// Bytes per row, of the side-by-side hexdump.
+const ROW_LENGTH: u32 = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeKind {
+    /// Only the new image has data
+    Added,
+    /// Only the old image has data
+    Removed,
+    /// Both images have data, but it differs
+    Changed,
+}
+
+impl ChangeKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ChangeKind::Added => "ADDED",
+            ChangeKind::Removed => "REMOVED",
+            ChangeKind::Changed => "CHANGED",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Change {
+    pub kind: ChangeKind,
+    pub range: AddressRange,
+}
+
+pub(crate) fn byte_at(regions: &[Region], address: u32) -> Option<u8> {
+    regions
+        .iter()
+        .find(|r| r.range().contains(address))
+        .map(|r| r.data()[(address - r.address()) as usize])
+}
+
+fn push_change(changes: &mut Vec<Change>, kind: ChangeKind, range: AddressRange) {
+    match changes.last_mut() {
+        Some(last) if last.kind == kind && last.range.end() == range.start() => {
+            last.range = AddressRange::new(last.range.start(), range.end());
+        }
+        _ => changes.push(Change { kind, range }),
+    }
+}
+
+/**
+ * Address ranges whose data was added, removed, or changed, between the old
+ * and the new 'Region's (which must not overlap, within each set).
+ */
+pub fn compare_regions(old: &[Region], new: &[Region]) -> Vec<Change> {
+    let mut points: Vec<u32> = old
+        .iter()
+        .chain(new.iter())
+        .flat_map(|r| [r.range().start(), r.range().end()])
+        .collect();
+    points.sort();
+    points.dedup();
+
+    // Each segment between two boundaries lies wholly inside, or outside, of
+    // each region
+    let mut changes = Vec::new();
+    for pair in points.windows(2) {
+        let range = AddressRange::new(pair[0], pair[1]);
+        let a = old.iter().find_map(|r| r.clip(&range));
+        let b = new.iter().find_map(|r| r.clip(&range));
+        match (a, b) {
+            (None, None) => (),
+            (Some(_), None) => push_change(&mut changes, ChangeKind::Removed, range),
+            (None, Some(_)) => push_change(&mut changes, ChangeKind::Added, range),
+            (Some(a), Some(b)) => {
+                for (i, (x, y)) in a.data().iter().zip(b.data().iter()).enumerate() {
+                    if x != y {
+                        let address = range.start() + i as u32;
+                        let byte = AddressRange::new(address, address + 1);
+                        push_change(&mut changes, ChangeKind::Changed, byte);
+                    }
+                }
+            }
+        }
+    }
+    changes
+}
+
+/**
+ * Indices of the packets that differ (in address, length, or data), including
+ * any packets that only one of the updates has.
+ */
+pub fn changed_packets(old: &[FirmwareUpdatePacket], new: &[FirmwareUpdatePacket]) -> Vec<usize> {
+    (0..old.len().max(new.len()))
+        .filter(|i| old.get(*i) != new.get(*i))
+        .collect()
+}
+
+/**
+ * Compact list of indices, e.g. '3, 7-9'.
+ */
+pub fn to_index_list(indices: &[usize]) -> String {
+    let mut runs: Vec<(usize, usize)> = Vec::new();
+    for i in indices.iter() {
+        match runs.last_mut() {
+            Some((_, last)) if *last + 1 == *i => *last = *i,
+            _ => runs.push((*i, *i)),
+        }
+    }
+    let runs: Vec<String> = runs
+        .iter()
+        .map(|(first, last)| match first == last {
+            true => format!("{}", first),
+            false => format!("{}-{}", first, last),
+        })
+        .collect();
+    runs.join(", ")
+}
+
+/**
+ * Side-by-side hexdump of the old and new bytes, in rows of 16 bytes, that
+ * cover the ranges (each row at most once), with '--' where an image has no
+ * data, and '*' marking the rows that differ.
+ */
+pub fn side_by_side(old: &[Region], new: &[Region], ranges: &[AddressRange]) -> Vec<String> {
+    let mut rows: Vec<u32> = ranges
+        .iter()
+        .flat_map(|r| {
+            let first = r.start() / ROW_LENGTH;
+            let last = (r.end() - 1) / ROW_LENGTH;
+            (first..=last).map(|x| x * ROW_LENGTH)
+        })
+        .collect();
+    rows.sort();
+    rows.dedup();
+
+    let hex = |bytes: &[Option<u8>]| {
+        let bytes: Vec<String> = bytes
+            .iter()
+            .map(|x| x.map_or("--".to_string(), |x| format!("{:02x}", x)))
+            .collect();
+        bytes.join(" ")
+    };
+    rows.iter()
+        .map(|row| {
+            let addresses = *row..=row.saturating_add(ROW_LENGTH - 1);
+            let a: Vec<Option<u8>> = addresses.clone().map(|x| byte_at(old, x)).collect();
+            let b: Vec<Option<u8>> = addresses.map(|x| byte_at(new, x)).collect();
+            let mark = if a == b { ' ' } else { '*' };
+            format!("{:08x} {} {} | {}", row, mark, hex(&a), hex(&b))
+        })
+        .collect()
+}
+
+//----------------------------------------------------------------------------
+// Tests
+//----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::{changed_packets, compare_regions, to_index_list, ChangeKind};
+    use crate::range::AddressRange;
+    use crate::region::Region;
+
+    #[test]
+    fn added_removed_changed() {
+        let old = vec![
+            Region::with_data(0x1000, vec![0; 0x20]),
+            Region::with_data(0x2000, vec![0; 0x10]),
+        ];
+        let mut data = vec![0; 0x30];
+        data[4] = 1;
+        data[5] = 1;
+        data[7] = 1;
+        let new = vec![Region::with_data(0x1000, data)];
+
+        let changes: Vec<(ChangeKind, AddressRange)> = compare_regions(&old, &new)
+            .iter()
+            .map(|c| (c.kind, c.range))
+            .collect();
+        assert_eq!(
+            changes,
+            [
+                (ChangeKind::Changed, AddressRange::new(0x1004, 0x1006)),
+                (ChangeKind::Changed, AddressRange::new(0x1007, 0x1008)),
+                (ChangeKind::Added, AddressRange::new(0x1020, 0x1030)),
+                (ChangeKind::Removed, AddressRange::new(0x2000, 0x2010)),
+            ]
+        );
+    }
+
+    #[test]
+    fn changed_packet_indices() {
+        let target = Default::default();
+        let old = Region::with_data(0x1000, vec![0; 1000]).to_packets(&target, false);
+        let mut data = vec![0; 1100];
+        data[300] = 1;
+        let new = Region::with_data(0x1000, data).to_packets(&target, false);
+        let indices = changed_packets(&old, &new);
+        assert_eq!(indices, [1, 5]);
+        assert_eq!(to_index_list(&[1, 4, 5, 6, 9]), "1, 4-6, 9");
+    }
+}
@@ -0,0 +1,64 @@
+use clap::ValueEnum;
+use sha2::{Digest, Sha256, Sha512};
+
+/**
+ * Cryptographic hash algorithms, for digests of the firmware update.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum DigestAlg {
+    Sha256,
+    Sha512,
+}
+
+impl DigestAlg {
+    /// Name for the summary, e.g. "SHA-256".
+    pub fn name(&self) -> &'static str {
+        match self {
+            DigestAlg::Sha256 => "SHA-256",
+            DigestAlg::Sha512 => "SHA-512",
+        }
+    }
+
+    /// Name for C declarations, e.g. "Sha256".
+    pub fn label(&self) -> &'static str {
+        match self {
+            DigestAlg::Sha256 => "Sha256",
+            DigestAlg::Sha512 => "Sha512",
+        }
+    }
+
+    pub fn digest(&self, data: &[u8]) -> Vec<u8> {
+        match self {
+            DigestAlg::Sha256 => Sha256::digest(data).to_vec(),
+            DigestAlg::Sha512 => Sha512::digest(data).to_vec(),
+        }
+    }
+}
+
+/**
+ * Lower-case hex-digits of the bytes (as printed by 'sha256sum').
+ */
+pub fn to_hex_string(bytes: &[u8]) -> String {
+    bytes.iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+//----------------------------------------------------------------------------
+// Tests
+//----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::{to_hex_string, DigestAlg};
+
+    #[test]
+    fn known_digests() {
+        assert_eq!(
+            to_hex_string(&DigestAlg::Sha256.digest(b"abc")),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            to_hex_string(&DigestAlg::Sha512.digest(b"abc")[0..16]),
+            "ddaf35a193617abacc417349ae204131"
+        );
+    }
+}
@@ -0,0 +1,208 @@
+use aes::{Aes128, Aes256};
+use cbc::cipher::block_padding::NoPadding;
+use cbc::cipher::{BlockEncryptMut, KeyIvInit, StreamCipher};
+use clap::ValueEnum;
+use rand_core::{OsRng, RngCore};
+use std::path::Path;
+
+use crate::error::Error;
+use crate::hexcrc::stm32_crc;
+use crate::patch::parse_hex_bytes;
+use crate::region::Region;
+use crate::target::Target;
+use crate::update::FirmwareUpdate;
+
+pub const AES_BLOCK_SIZE: usize = 16;
+
+/**
+ * AES block-cipher modes, for encrypting the firmware payload.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum CipherMode {
+    #[default]
+    Ctr,
+    Cbc,
+}
+
+/**
+ * AES key (128 or 256 bits), mode, and initialisation vector.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Encryption {
+    key: Vec<u8>,
+    mode: CipherMode,
+    iv: [u8; AES_BLOCK_SIZE],
+}
+
+/**
+ * Read an AES key, as either the raw 16 (or 32) bytes, or as hex-digits.
+ */
+pub fn read_key_file(path: &Path) -> Result<Vec<u8>, Error> {
+    let bytes = std::fs::read(path)
+        .map_err(|e| Error::Encryption(format!("cannot read '{}' ({})", path.display(), e)))?;
+    let key = match std::str::from_utf8(&bytes) {
+        Ok(text) if text.trim().chars().all(|c| c.is_ascii_hexdigit()) => {
+            parse_hex_bytes(text).map_err(Error::Encryption)?
+        }
+        _ => bytes,
+    };
+    match key.len() {
+        16 | 32 => Ok(key),
+        n => Err(Error::Encryption(format!(
+            "'{}' holds a {}-bit key (expected AES-128, or AES-256)",
+            path.display(),
+            n * 8
+        ))),
+    }
+}
+
+/**
+ * Parse a (16-byte) initialisation vector, given as hex-digits.
+ */
+pub fn parse_iv(arg: &str) -> Result<[u8; AES_BLOCK_SIZE], String> {
+    parse_hex_bytes(arg)?
+        .try_into()
+        .map_err(|_| format!("expected a {}-byte IV, found '{}'", AES_BLOCK_SIZE, arg))
+}
+
+impl Encryption {
+    /**
+     * Use the given IV, or a random IV, if none is given.
+     */
+    pub fn new(key: Vec<u8>, mode: CipherMode, iv: Option<[u8; AES_BLOCK_SIZE]>) -> Self {
+        let iv = iv.unwrap_or_else(|| {
+            let mut iv = [0; AES_BLOCK_SIZE];
+            OsRng.fill_bytes(&mut iv);
+            iv
+        });
+        Self { key, mode, iv }
+    }
+
+    pub fn iv(&self) -> &[u8] {
+        &self.iv
+    }
+
+    /// Name for the summary, e.g. "AES-128-CTR".
+    pub fn name(&self) -> String {
+        let mode = match self.mode {
+            CipherMode::Ctr => "CTR",
+            CipherMode::Cbc => "CBC",
+        };
+        format!("AES-{}-{}", self.key.len() * 8, mode)
+    }
+
+    /**
+     * Encrypt the data, which must be a whole number of blocks, for CBC.
+     */
+    pub fn encrypt(&self, data: &[u8]) -> Vec<u8> {
+        let mut buf = data.to_vec();
+        let (key, iv) = (self.key.as_slice(), self.iv.as_slice());
+        match (self.mode, self.key.len()) {
+            (CipherMode::Ctr, 16) => {
+                ctr::Ctr128BE::<Aes128>::new(key.into(), iv.into()).apply_keystream(&mut buf)
+            }
+            (CipherMode::Ctr, _) => {
+                ctr::Ctr128BE::<Aes256>::new(key.into(), iv.into()).apply_keystream(&mut buf)
+            }
+            (CipherMode::Cbc, 16) => {
+                cbc::Encryptor::<Aes128>::new(key.into(), iv.into())
+                    .encrypt_padded_mut::<NoPadding>(&mut buf, data.len())
+                    .expect("CBC data must be whole blocks");
+            }
+            (CipherMode::Cbc, _) => {
+                cbc::Encryptor::<Aes256>::new(key.into(), iv.into())
+                    .encrypt_padded_mut::<NoPadding>(&mut buf, data.len())
+                    .expect("CBC data must be whole blocks");
+            }
+        }
+        buf
+    }
+
+    /**
+     * Encrypt the data of the firmware update, and then re-packetise it, so
+     * that each packet (and its CRC16) carries ciphertext, while the CRC32
+     * covers the plaintext. The plaintext is zero-padded to whole blocks (for
+     * CBC), and to the program granularity, unless a CRC is to be appended, so
+     * that the decrypted data is exactly what gets programmed.
+     */
+    pub fn encrypt_update(
+        &self,
+        update: &FirmwareUpdate,
+        target: &Target,
+        append_crc: bool,
+    ) -> FirmwareUpdate {
+        let base = match update.packets().first() {
+            Some(p) => p.address(),
+            None => return update.clone(),
+        };
+        let mut plain = update.to_vec();
+        let mut align = 1;
+        if self.mode == CipherMode::Cbc {
+            align = AES_BLOCK_SIZE;
+        }
+        if !append_crc {
+            align = align.max(target.granularity());
+        }
+        plain.resize(plain.len().next_multiple_of(align), 0);
+
+        let cipher = Region::with_data(base, self.encrypt(&plain));
+        FirmwareUpdate::encrypted(cipher.to_packets(target, false), stm32_crc(&plain))
+    }
+}
+
+//----------------------------------------------------------------------------
+// Tests
+//----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_iv, CipherMode, Encryption};
+    use crate::patch::parse_hex_bytes;
+    use crate::region::Region;
+    use crate::update::FirmwareUpdate;
+
+    // Test vectors from NIST SP 800-38A (F.2.1 and F.5.1)
+    const KEY: &str = "2b7e151628aed2a6abf7158809cf4f3c";
+    const PLAIN: &str = "6bc1bee22e409f96e93d7e117393172a";
+
+    #[test]
+    fn aes_test_vectors() {
+        let key = parse_hex_bytes(KEY).unwrap();
+        let plain = parse_hex_bytes(PLAIN).unwrap();
+
+        let iv = parse_iv("f0f1f2f3f4f5f6f7f8f9fafbfcfdfeff").unwrap();
+        let ctr = Encryption::new(key.clone(), CipherMode::Ctr, Some(iv));
+        let expect = parse_hex_bytes("874d6191b620e3261bef6864990db6ce").unwrap();
+        assert_eq!(ctr.encrypt(&plain), expect);
+        assert_eq!(ctr.name(), "AES-128-CTR");
+
+        let iv = parse_iv("000102030405060708090a0b0c0d0e0f").unwrap();
+        let cbc = Encryption::new(key, CipherMode::Cbc, Some(iv));
+        let expect = parse_hex_bytes("7649abac8119b246cee98e9b12e9197d").unwrap();
+        assert_eq!(cbc.encrypt(&plain), expect);
+    }
+
+    #[test]
+    fn encrypted_update_crcs() {
+        let data: Vec<u8> = (0..500).map(|x| x as u8).collect();
+        let region = Region::with_data(0x0800_0000, data.clone());
+        let target = Default::default();
+        let update = FirmwareUpdate::new(region.to_packets(&target, true));
+
+        let key = parse_hex_bytes(KEY).unwrap();
+        let cbc = Encryption::new(key, CipherMode::Cbc, None);
+        let encrypted = cbc.encrypt_update(&update, &target, false);
+
+        // Plaintext CRC32, over the data padded to whole blocks
+        let mut plain = data;
+        plain.resize(512, 0);
+        assert_eq!(encrypted.crc32(), crate::hexcrc::stm32_crc(&plain));
+        assert_eq!(encrypted.to_vec(), cbc.encrypt(&plain));
+
+        // Packet CRC16s cover the transmitted (cipher-)data
+        for p in encrypted.packets() {
+            let crc16 = crate::hexcrc::calc_ccitt_crc(&p.to_vec(), p.len() as u32);
+            assert_eq!(p.crc16(), crc16);
+        }
+    }
+}
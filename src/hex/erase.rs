@@ -0,0 +1,146 @@
+use serde::Serialize;
+
+use crate::device::{Device, Sector};
+use crate::error::Error;
+use crate::packet::FirmwareUpdatePacket;
+use crate::range::AddressRange;
+use crate::region::Region;
+
+/**
+ * Flash sectors (pages) that the bootloader must erase before programming the
+ * firmware update.
+ */
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ErasePlan {
+    device: String,
+    sectors: Vec<Sector>,
+}
+
+impl ErasePlan {
+    /**
+     * Compute the erase plan for the given (merged) 'Region's, which must all
+     * lie within the (writable) flash of the device.
+     */
+    pub fn new(device: &Device, regions: &[Region]) -> Result<Self, Error> {
+        let sectors = device.validate(regions)?;
+        Ok(Self {
+            device: device.name().to_string(),
+            sectors,
+        })
+    }
+
+    pub fn sectors(&self) -> &[Sector] {
+        &self.sectors
+    }
+
+    /**
+     * Total number of bytes to erase.
+     */
+    pub fn size(&self) -> usize {
+        self.sectors.iter().fold(0, |s, x| s + x.size as usize)
+    }
+
+    /**
+     * Coalesce adjacent sectors into contiguous address ranges.
+     */
+    pub fn ranges(&self) -> Vec<AddressRange> {
+        let mut ranges: Vec<AddressRange> = Vec::new();
+        for next in self.sectors.iter().filter_map(|s| s.range()) {
+            match ranges.last_mut() {
+                Some(last) if last.end() == next.start() => {
+                    *last = AddressRange::new(last.start(), next.end());
+                }
+                _ => ranges.push(next),
+            }
+        }
+        ranges
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+
+    /**
+     * Keep only the sectors that overlap any of the ranges (e.g. the pages of
+     * a delta update, which may differ in size from the sectors).
+     */
+    pub fn retain_overlapping(&mut self, ranges: &[AddressRange]) {
+        self.sectors.retain(|s| {
+            s.range()
+                .is_some_and(|sector| ranges.iter().any(|r| r.overlaps(&sector)))
+        });
+    }
+
+    /**
+     * One erase-command packet per sector, to precede the data packets.
+     */
+    pub fn to_packets(&self) -> Vec<FirmwareUpdatePacket> {
+        self.sectors
+            .iter()
+            .map(|s| FirmwareUpdatePacket::erase(s.address, s.size))
+            .collect()
+    }
+}
+
+//----------------------------------------------------------------------------
+// Tests
+//----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::ErasePlan;
+    use crate::device::Device;
+    use crate::range::AddressRange;
+    use crate::region::Region;
+    use ihex::Record;
+
+    fn make_region(base: u32, size: usize) -> Region {
+        let mut records = vec![
+            Record::ExtendedLinearAddress((base >> 16) as u16),
+            Record::Data {
+                offset: base as u16,
+                value: vec![0xa5; size],
+            },
+            Record::EndOfFile,
+        ];
+        Region::build_regions(&mut records).pop().unwrap()
+    }
+
+    #[test]
+    fn erase_plan_covers_regions() {
+        let device = Device::builtin("stm32g431xb").unwrap();
+        let regions = vec![
+            make_region(0x0800_c800, 0x900),
+            make_region(0x0801_0000, 0x10),
+            make_region(0x0801_0010, 0x800),
+        ];
+        let plan = ErasePlan::new(&device, &regions).unwrap();
+        assert_eq!(plan.sectors().len(), 4);
+        assert_eq!(plan.size(), 4 * 0x800);
+        assert_eq!(
+            plan.ranges(),
+            vec![
+                AddressRange::new(0x0800_c800, 0x0800_d800),
+                AddressRange::new(0x0801_0000, 0x0801_1000),
+            ]
+        );
+
+        let packets = plan.to_packets();
+        assert_eq!(packets.len(), 4);
+        assert_eq!(packets[1].address(), 0x0800_d000);
+        assert!(packets.iter().all(|p| p.to_bytes()[1] == b'e'));
+    }
+
+    #[test]
+    fn retain_sectors_of_larger_pages() {
+        let device = Device::builtin("stm32g431xb").unwrap();
+        let regions = vec![make_region(0x0800_c000, 0x2000)];
+        let mut plan = ErasePlan::new(&device, &regions).unwrap();
+        assert_eq!(plan.sectors().len(), 4);
+
+        // A 4 kB page holds two 2 kB sectors, which must both be erased
+        plan.retain_overlapping(&[AddressRange::new(0x0800_d000, 0x0800_e000)]);
+        let addresses: Vec<u32> = plan.sectors().iter().map(|s| s.address).collect();
+        assert_eq!(addresses, [0x0800_d000, 0x0800_d800]);
+    }
+}
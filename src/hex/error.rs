@@ -0,0 +1,143 @@
+use std::fmt;
+
+use crate::range::AddressRange;
+
+/**
+ * Errors found while checking, or transforming, the firmware image.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// Input file could not be read.
+    Input(String),
+    /// Device profile could not be found, read, or parsed.
+    Device(String),
+    /// Data lies (partially) outside of the flash banks of the device.
+    NotInFlash {
+        device: String,
+        address: u32,
+        size: usize,
+    },
+    /// Data overlaps a reserved area of the device.
+    Reserved {
+        name: String,
+        address: u32,
+        size: usize,
+    },
+    /// Data (once relocated, if 'offset' is non-zero) would not fit within the
+    /// 32-bit address space.
+    AddressOverflow {
+        address: u32,
+        size: usize,
+        offset: i64,
+    },
+    /// Patch could not be parsed, or read from file.
+    Patch(String),
+    /// Patched bytes are not covered by the firmware data.
+    NotCovered { address: u32, size: usize },
+    /// Batch units could not be read, or their images written.
+    Batch(String),
+    /// Metadata-header layout is invalid, or a field has no value.
+    Metadata(String),
+    /// MCUboot image could not be built from the firmware data.
+    Mcuboot(String),
+    /// Key could not be read, signature could not be placed, or it is invalid.
+    Signature(String),
+    /// Encryption key could not be read, or is the wrong size.
+    Encryption(String),
+    /// Compressed data does not decompress to the image.
+    Compression(String),
+    /// Image does not match the expected CRC32, or the reference image.
+    Verify(String),
+    /// Packets could not be sent to the port.
+    Send(String),
+    /// C include file could not be parsed, or does not match the image.
+    Header(String),
+    /// Outputs cannot be written as requested.
+    Output(String),
+    /// Config file could not be read, or holds an invalid value.
+    Config(String),
+    /// Data records overlap, and the overlap policy does not allow it.
+    Overlap {
+        first_line: usize,
+        first: AddressRange,
+        second_line: usize,
+        second: AddressRange,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Input(msg) => write!(f, "input: {}", msg),
+            Error::Device(msg) => write!(f, "device profile: {}", msg),
+            Error::NotInFlash {
+                device,
+                address,
+                size,
+            } => write!(
+                f,
+                "data at 0x{:08x} (size = {}) is not within the flash of '{}'",
+                address, size, device
+            ),
+            Error::Reserved {
+                name,
+                address,
+                size,
+            } => write!(
+                f,
+                "data at 0x{:08x} (size = {}) overlaps reserved area '{}'",
+                address, size, name
+            ),
+            Error::AddressOverflow {
+                address,
+                size,
+                offset: 0,
+            } => write!(
+                f,
+                "data at 0x{:08x} (size = {}) extends past the 32-bit address space",
+                address, size
+            ),
+            Error::AddressOverflow {
+                address,
+                size,
+                offset,
+            } => write!(
+                f,
+                "cannot move data at 0x{:08x} (size = {}) by {}0x{:x}",
+                address,
+                size,
+                if *offset < 0 { "-" } else { "+" },
+                offset.unsigned_abs()
+            ),
+            Error::Patch(msg) => write!(f, "patch: {}", msg),
+            Error::NotCovered { address, size } => write!(
+                f,
+                "patch at 0x{:08x} (size = {}) is not covered by the firmware data (see '--extend')",
+                address, size
+            ),
+            Error::Batch(msg) => write!(f, "batch: {}", msg),
+            Error::Metadata(msg) => write!(f, "metadata header: {}", msg),
+            Error::Mcuboot(msg) => write!(f, "MCUboot image: {}", msg),
+            Error::Signature(msg) => write!(f, "signature: {}", msg),
+            Error::Encryption(msg) => write!(f, "encryption: {}", msg),
+            Error::Compression(msg) => write!(f, "compression: {}", msg),
+            Error::Verify(msg) => write!(f, "verify: {}", msg),
+            Error::Send(msg) => write!(f, "send: {}", msg),
+            Error::Header(msg) => write!(f, "C header: {}", msg),
+            Error::Output(msg) => write!(f, "output: {}", msg),
+            Error::Config(msg) => write!(f, "config: {}", msg),
+            Error::Overlap {
+                first_line,
+                first,
+                second_line,
+                second,
+            } => write!(
+                f,
+                "data at {} (line {}) overlaps data at {} (line {})",
+                second, second_line, first, first_line
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
@@ -0,0 +1,128 @@
+use crate::append::{CRC32_SUFFIX, IMAGE_SUFFIX};
+use crate::error::Error;
+use crate::range::parse_number;
+
+/**
+ * Firmware image, as declared by a (generated, or hand-edited) C include
+ * file.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IncludeImage {
+    pub crc32: u32,
+    pub bytes: Vec<u8>,
+}
+
+/**
+ * Remove '/* .. */' and '// ..' comments.
+ */
+fn strip_comments(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while !rest.is_empty() {
+        if let Some(tail) = rest.strip_prefix("/*") {
+            rest = tail.split_once("*/").map(|(_, x)| x).unwrap_or("");
+            out.push(' ');
+        } else if let Some(tail) = rest.strip_prefix("//") {
+            rest = tail.find('\n').map(|n| &tail[n..]).unwrap_or("");
+        } else {
+            let c = rest.chars().next().unwrap();
+            out.push(c);
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+    out
+}
+
+/**
+ * Parse a C integer literal, ignoring any 'u'/'l' suffixes.
+ */
+fn parse_literal(arg: &str) -> Result<u64, Error> {
+    let arg = arg.trim();
+    let digits = arg.trim_end_matches(['u', 'U', 'l', 'L']);
+    parse_number(digits).map_err(|e| Error::Header(format!("'{}' ({})", arg, e)))
+}
+
+/**
+ * Text after the declaration of 'name', and its '=', or '= {'.
+ */
+fn initialiser<'a>(text: &'a str, name: &str) -> Result<&'a str, Error> {
+    let missing = || Error::Header(format!("no definition of '{}'", name));
+    let start = text.find(name).ok_or_else(missing)?;
+    let (_, value) = text[start..].split_once('=').ok_or_else(missing)?;
+    Ok(value)
+}
+
+/**
+ * Parse the CRC32 constant (e.g. 'kBootloaderFirmwareCrc'), and the bytes of
+ * the image initialiser (e.g. 'kBootloaderFirmwareBin[]'), of a C include
+ * file, with the given symbol prefix.
+ */
+pub fn parse_include_text(text: &str, prefix: &str) -> Result<IncludeImage, Error> {
+    let text = strip_comments(text);
+
+    let value = initialiser(&text, &format!("{}{}", prefix, CRC32_SUFFIX))?;
+    let value = value.split(';').next().unwrap();
+    let crc32 = u32::try_from(parse_literal(value)?)
+        .map_err(|_| Error::Header(format!("'{}' exceeds 32 bits", value.trim())))?;
+
+    let name = format!("{}{}", prefix, IMAGE_SUFFIX);
+    let value = initialiser(&text, &name)?;
+    let body = value
+        .trim_start()
+        .strip_prefix('{')
+        .and_then(|x| x.split_once('}'))
+        .map(|(body, _)| body)
+        .ok_or_else(|| Error::Header(format!("'{}' has no '{{ .. }}' initialiser", name)))?;
+    let bytes = body
+        .split(',')
+        .filter(|x| !x.trim().is_empty())
+        .map(|x| {
+            let value = parse_literal(x)?;
+            u8::try_from(value).map_err(|_| Error::Header(format!("'{}' exceeds 8 bits", x.trim())))
+        })
+        .collect::<Result<_, _>>()?;
+    Ok(IncludeImage { crc32, bytes })
+}
+
+//----------------------------------------------------------------------------
+// Tests
+//----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::parse_include_text;
+    use crate::append::{to_include_text, DEFAULT_SYMBOL_PREFIX as PREFIX};
+    use crate::digest::DigestAlg;
+    use crate::hexcrc::stm32_crc;
+    use crate::region::Region;
+    use crate::update::FirmwareUpdate;
+
+    #[test]
+    fn include_round_trip() {
+        let data: Vec<u8> = (0..300).map(|x| (x * 7) as u8).collect();
+        let region = Region::with_data(0x0800_0000, data.clone());
+        let update = FirmwareUpdate::new(region.to_packets(&Default::default(), false));
+
+        let text = to_include_text(&update, false, &[DigestAlg::Sha256], PREFIX);
+        let image = parse_include_text(&text, PREFIX).unwrap();
+        assert_eq!(image.bytes, data);
+        assert_eq!(image.crc32, update.crc32());
+
+        // The appended CRC32 is part of the data, and of the declared CRC32
+        let text = to_include_text(&update, true, &[], PREFIX);
+        let image = parse_include_text(&text, PREFIX).unwrap();
+        assert_eq!(image.bytes.len(), data.len() + 4);
+        assert_eq!(image.crc32, stm32_crc(&image.bytes));
+    }
+
+    #[test]
+    fn hand_edited_include() {
+        let text = "const uint32_t kBootloaderFirmwareCrc = 0x1234ABCDUL; // edited\n\
+                    const uint8_t kBootloaderFirmwareBin[] = {\n\t0x01, /* two */ 2,\n\t0xff,\n};\n";
+        let image = parse_include_text(text, PREFIX).unwrap();
+        assert_eq!(image.crc32, 0x1234_abcd);
+        assert_eq!(image.bytes, [1, 2, 0xff]);
+        let text = "const uint8_t kBootloaderFirmwareBin[] = { 0x100 };";
+        assert!(parse_include_text(text, PREFIX).is_err());
+    }
+}
@@ -24,6 +24,11 @@ pub fn calc_stm32_crc(packets: &[FirmwareUpdatePacket]) -> u32 {
     digest.finalize()
 }
 
+pub fn stm32_crc(data: &[u8]) -> u32 {
+    let crc = crc::Crc::<u32>::new(&CUSTOM_ALG);
+    crc.checksum(data)
+}
+
 pub fn calc_ccitt_crc(data: &[u8], size: u32) -> u16 {
     let mut crc: u16 = 0xffff;
 
@@ -34,7 +39,7 @@ pub fn calc_ccitt_crc(data: &[u8], size: u32) -> u16 {
     while current_byte < total_bytes {
         let value = data[current_byte];
 
-        crc = (crc >> 8) | (crc << 8);
+        crc = crc.rotate_left(8);
         crc ^= value as u16; // Cast to u16 for bitwise xor
 
         crc ^= (crc & 0xff) >> 4;
@@ -77,7 +82,7 @@ mod tests {
 
     #[allow(unused)]
     fn make_test_data() -> Vec<u8> {
-        return TEST_LINE.as_bytes().to_vec();
+        TEST_LINE.as_bytes().to_vec()
     }
 
     #[test]
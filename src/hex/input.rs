@@ -0,0 +1,64 @@
+use ihex::Record;
+use std::path::Path;
+
+use crate::error::Error;
+use crate::overlap::{resolve_overlaps, OverlapPolicy};
+use crate::region::Region;
+use crate::stdio::read_input_text;
+
+/// HEX records, along with their (1-based) source line numbers.
+pub type NumberedRecords = Vec<(usize, Record)>;
+
+/**
+ * Parse the records of a HEX file, along with their (1-based) line numbers.
+ * Parsing stops at the first invalid record, or after the end-of-file record.
+ */
+pub fn read_records(text: &str) -> NumberedRecords {
+    let mut records = Vec::new();
+    for (num, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        match Record::from_record_string(line) {
+            Ok(Record::EndOfFile) => {
+                records.push((num + 1, Record::EndOfFile));
+                break;
+            }
+            Ok(record) => records.push((num + 1, record)),
+            Err(_) => break,
+        }
+    }
+    records
+}
+
+/**
+ * Execution start address, from the (last) start linear address record, if
+ * any.
+ */
+pub fn start_address(records: &NumberedRecords) -> Option<u32> {
+    records.iter().rev().find_map(|(_, r)| match r {
+        Record::StartLinearAddress(address) => Some(*address),
+        _ => None,
+    })
+}
+
+/**
+ * Read the 'Region's of a HEX file, resolving any overlapping records with
+ * the given policy.
+ */
+pub fn read_hex_file(path: &Path, policy: OverlapPolicy) -> Result<Vec<Region>, Error> {
+    let text = read_input_text(path)
+        .map_err(|e| Error::Input(format!("cannot read '{}' ({})", path.display(), e)))?;
+    read_hex_text(&text, policy)
+}
+
+/**
+ * Read the 'Region's of the text of a HEX file, resolving any overlapping
+ * records with the given policy.
+ */
+pub fn read_hex_text(text: &str, policy: OverlapPolicy) -> Result<Vec<Region>, Error> {
+    let (records, _) = resolve_overlaps(&read_records(text), policy)?;
+    let mut records: Vec<Record> = records.into_iter().map(|(_, r)| r).collect();
+    Ok(Region::build_regions(&mut records))
+}
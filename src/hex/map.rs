@@ -0,0 +1,216 @@
+use crate::diff::byte_at;
+use crate::error::Error;
+use crate::range::AddressRange;
+use crate::region::{merge_regions, Region};
+use crate::target::Target;
+
+/// Bytes per row, of the hexdump.
+const ROW_LENGTH: u32 = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapKind {
+    /// Data from the HEX file
+    Data,
+    /// Gap (of less than the program granularity) zero-filled by merging
+    Fill,
+    /// Gap zero-filled, to build the single (mono-)region
+    Gap,
+    /// Zero-padding of the final packet, to the program granularity
+    Padding,
+    /// Appended CRC32
+    Crc,
+}
+
+impl MapKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            MapKind::Data => "DATA",
+            MapKind::Fill => "FILL",
+            MapKind::Gap => "GAP",
+            MapKind::Padding => "PAD",
+            MapKind::Crc => "CRC32",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MapEntry {
+    pub kind: MapKind,
+    pub range: AddressRange,
+}
+
+/**
+ * Memory map of the firmware update built from the 'Region's: the data, the
+ * gaps between the regions (which are zero-filled), the padding of the final
+ * packet, and the appended CRC32 (if any), which must all end at, or below,
+ * 0xffffffff.
+ */
+pub fn memory_map(
+    regions: &[Region],
+    target: &Target,
+    append_crc: bool,
+) -> Result<Vec<MapEntry>, Error> {
+    let merged = merge_regions(regions, target);
+    let mut map = Vec::new();
+    for (i, r) in regions.iter().enumerate() {
+        map.push(MapEntry {
+            kind: MapKind::Data,
+            range: r.range(),
+        });
+        let next = match regions.get(i + 1) {
+            Some(next) if next.address() > r.range().end() => next,
+            _ => continue,
+        };
+        let range = AddressRange::new(r.range().end(), next.address());
+        let kind = match merged.iter().any(|m| m.range().contains(range.start())) {
+            true => MapKind::Fill,
+            false => MapKind::Gap,
+        };
+        map.push(MapEntry { kind, range });
+    }
+
+    let update = crate::make_update(regions, target, append_crc);
+    if let (Some(first), Some(last)) = (regions.first(), regions.last()) {
+        let crc = if append_crc { 4 } else { 0 };
+        let overflow = Error::AddressOverflow {
+            address: first.address(),
+            size: update.len() + crc,
+            offset: 0,
+        };
+        let end = AddressRange::with_size(first.address(), update.len() as u32)
+            .ok_or(overflow.clone())?
+            .end();
+        if end > last.range().end() {
+            map.push(MapEntry {
+                kind: MapKind::Padding,
+                range: AddressRange::new(last.range().end(), end),
+            });
+        }
+        if append_crc {
+            map.push(MapEntry {
+                kind: MapKind::Crc,
+                range: AddressRange::with_size(end, 4).ok_or(overflow)?,
+            });
+        }
+    }
+    Ok(map)
+}
+
+/**
+ * Dump the bytes of the 'Region's within the range, in the style of
+ * 'hexdump -C', with '--' for addresses that hold no data.
+ */
+pub fn hexdump(regions: &[Region], range: &AddressRange) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut row = range.start() - range.start() % ROW_LENGTH;
+    while row < range.end() {
+        let mut hex = String::new();
+        let mut text = String::new();
+        for i in 0..ROW_LENGTH {
+            let address = row + i;
+            let byte = match range.contains(address) {
+                true => byte_at(regions, address),
+                false => None,
+            };
+            if i == ROW_LENGTH / 2 {
+                hex.push(' ');
+            }
+            match byte {
+                Some(x) => {
+                    hex.push_str(&format!(" {:02x}", x));
+                    text.push(if x.is_ascii_graphic() || x == b' ' {
+                        x as char
+                    } else {
+                        '.'
+                    });
+                }
+                None => {
+                    hex.push_str(" --");
+                    text.push(' ');
+                }
+            }
+        }
+        lines.push(format!("{:08x} {}  |{}|", row, hex, text));
+        row = match row.checked_add(ROW_LENGTH) {
+            Some(next) => next,
+            None => break,
+        };
+    }
+    lines
+}
+
+//----------------------------------------------------------------------------
+// Tests
+//----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::{hexdump, memory_map, MapKind};
+    use crate::error::Error;
+    use crate::range::AddressRange;
+    use crate::region::Region;
+
+    #[test]
+    fn map_fills_and_crc() {
+        let regions = vec![
+            Region::with_data(0x1000, vec![1; 0x1d]),
+            Region::with_data(0x1020, vec![2; 0x10]),
+            Region::with_data(0x1100, vec![3; 0x03]),
+        ];
+        let kinds: Vec<(MapKind, AddressRange)> = memory_map(&regions, &Default::default(), true)
+            .unwrap()
+            .iter()
+            .map(|e| (e.kind, e.range))
+            .collect();
+        assert_eq!(
+            kinds,
+            [
+                (MapKind::Data, AddressRange::new(0x1000, 0x101d)),
+                (MapKind::Fill, AddressRange::new(0x101d, 0x1020)),
+                (MapKind::Data, AddressRange::new(0x1020, 0x1030)),
+                (MapKind::Gap, AddressRange::new(0x1030, 0x1100)),
+                (MapKind::Data, AddressRange::new(0x1100, 0x1103)),
+                (MapKind::Crc, AddressRange::new(0x1103, 0x1107)),
+            ]
+        );
+
+        // Without an appended CRC32, the final packet is padded instead
+        let map = memory_map(&regions, &Default::default(), false).unwrap();
+        let last = map.last().unwrap();
+        assert_eq!(
+            (last.kind, last.range),
+            (MapKind::Padding, AddressRange::new(0x1103, 0x1108))
+        );
+    }
+
+    #[test]
+    fn reject_crc_past_4gb() {
+        let regions = vec![Region::with_data(0xffff_ffe0, vec![1; 0x0c])];
+        assert!(memory_map(&regions, &Default::default(), true).is_ok());
+
+        // Neither the CRC32, nor the padding (without the CRC32), fits
+        let regions = vec![Region::with_data(0xffff_fff0, vec![1; 0x0c])];
+        assert!(memory_map(&regions, &Default::default(), false).is_err());
+        assert!(matches!(
+            memory_map(&regions, &Default::default(), true),
+            Err(Error::AddressOverflow {
+                address: 0xffff_fff0,
+                size: 0x10,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn hexdump_rows() {
+        let regions = vec![Region::with_data(0x1004, b"Hello, world!\n".to_vec())];
+        let lines = hexdump(&regions, &AddressRange::new(0x1000, 0x1014));
+        assert_eq!(
+            lines,
+            [
+                "00001000  -- -- -- -- 48 65 6c 6c  6f 2c 20 77 6f 72 6c 64  |    Hello, world|",
+                "00001010  21 0a -- -- -- -- -- --  -- -- -- -- -- -- -- --  |!.              |",
+            ]
+        );
+    }
+}
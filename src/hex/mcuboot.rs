@@ -0,0 +1,190 @@
+use sha2::{Digest, Sha256};
+
+use crate::error::Error;
+use crate::range::parse_number;
+use crate::region::Region;
+
+// See 'bootutil/include/bootutil/image.h', within the MCUboot repository.
+pub const IMAGE_MAGIC: u32 = 0x96f3_b83d;
+pub const IMAGE_HEADER_SIZE: usize = 32;
+pub const IMAGE_F_RAM_LOAD: u32 = 0x0000_0020;
+pub const IMAGE_TLV_INFO_MAGIC: u16 = 0x6907;
+pub const IMAGE_TLV_SHA256: u16 = 0x10;
+
+/**
+ * MCUboot image version, 'MAJOR.MINOR.REVISION+BUILD'.
+ */
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ImageVersion {
+    pub major: u8,
+    pub minor: u8,
+    pub revision: u16,
+    pub build: u32,
+}
+
+impl ImageVersion {
+    fn to_bytes(self) -> Vec<u8> {
+        let mut bytes = vec![self.major, self.minor];
+        bytes.extend(self.revision.to_le_bytes());
+        bytes.extend(self.build.to_le_bytes());
+        bytes
+    }
+}
+
+/**
+ * Parse an image version, as 'MAJOR[.MINOR[.REVISION]][+BUILD]' (the same
+ * syntax as 'imgtool sign --version').
+ */
+pub fn parse_image_version(arg: &str) -> Result<ImageVersion, String> {
+    let err = || format!("invalid image version '{}'", arg);
+    let (version, build) = match arg.split_once('+') {
+        Some((version, build)) => (version, parse_number(build)?),
+        None => (arg, 0),
+    };
+    let parts: Vec<u64> = version
+        .split('.')
+        .map(|x| x.parse::<u64>().map_err(|_| err()))
+        .collect::<Result<_, _>>()?;
+    if parts.is_empty() || parts.len() > 3 {
+        return Err(err());
+    }
+    let part = |i: usize, max: u64| match parts.get(i) {
+        Some(x) if *x > max => Err(err()),
+        Some(x) => Ok(*x),
+        None => Ok(0),
+    };
+    Ok(ImageVersion {
+        major: part(0, 0xff)? as u8,
+        minor: part(1, 0xff)? as u8,
+        revision: part(2, 0xffff)? as u16,
+        build: u32::try_from(build).map_err(|_| err())?,
+    })
+}
+
+/**
+ * Options for wrapping an image in an MCUboot header and TLV trailer.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct McubootOptions {
+    pub header_size: u16,
+    pub version: ImageVersion,
+    pub load_addr: Option<u32>,
+}
+
+impl Default for McubootOptions {
+    fn default() -> Self {
+        Self {
+            header_size: 0x200,
+            version: ImageVersion::default(),
+            load_addr: None,
+        }
+    }
+}
+
+fn image_header(image_size: usize, options: &McubootOptions) -> Vec<u8> {
+    let flags = match options.load_addr {
+        Some(_) => IMAGE_F_RAM_LOAD,
+        None => 0,
+    };
+    let mut header = Vec::with_capacity(options.header_size as usize);
+    header.extend(IMAGE_MAGIC.to_le_bytes());
+    header.extend(options.load_addr.unwrap_or(0).to_le_bytes());
+    header.extend(options.header_size.to_le_bytes());
+    header.extend(0u16.to_le_bytes()); // No protected TLVs
+    header.extend((image_size as u32).to_le_bytes());
+    header.extend(flags.to_le_bytes());
+    header.extend(options.version.to_bytes());
+    header.extend(0u32.to_le_bytes());
+    header.resize(options.header_size as usize, 0);
+    header
+}
+
+/**
+ * Wrap the (single, contiguous) 'Region' as an MCUboot image: the header is
+ * placed in front of the image data (like 'imgtool sign --pad-header'), and
+ * then the (unprotected) TLV area, with the SHA-256 hash of the header and
+ * image, is appended.
+ */
+pub fn to_mcuboot_region(region: &Region, options: &McubootOptions) -> Result<Region, Error> {
+    let header_size = options.header_size as usize;
+    if header_size < IMAGE_HEADER_SIZE {
+        return Err(Error::Mcuboot(format!(
+            "header size must be at least {} bytes",
+            IMAGE_HEADER_SIZE
+        )));
+    }
+    let base = region
+        .address()
+        .checked_sub(header_size as u32)
+        .ok_or(Error::Mcuboot(format!(
+            "no room for the header before 0x{:08x}",
+            region.address()
+        )))?;
+
+    let mut image = image_header(region.len(), options);
+    image.extend(region.data());
+    let hash = Sha256::digest(&image);
+
+    let tlv_size = 4 + 4 + hash.len();
+    image.extend(IMAGE_TLV_INFO_MAGIC.to_le_bytes());
+    image.extend((tlv_size as u16).to_le_bytes());
+    image.extend(IMAGE_TLV_SHA256.to_le_bytes());
+    image.extend((hash.len() as u16).to_le_bytes());
+    image.extend(hash);
+    Ok(Region::with_data(base, image))
+}
+
+//----------------------------------------------------------------------------
+// Tests
+//----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_image_version, to_mcuboot_region, ImageVersion, McubootOptions};
+    use crate::region::Region;
+    use sha2::{Digest, Sha256};
+
+    #[test]
+    fn parse_image_versions() {
+        let version = ImageVersion {
+            major: 1,
+            minor: 2,
+            revision: 3,
+            build: 4,
+        };
+        assert_eq!(parse_image_version("1.2.3+4"), Ok(version));
+        assert_eq!(parse_image_version("1.2").unwrap().minor, 2);
+        assert!(parse_image_version("1.2.3.4").is_err());
+        assert!(parse_image_version("256").is_err());
+    }
+
+    #[test]
+    fn mcuboot_image_layout() {
+        let data: Vec<u8> = (0..100).map(|x| x as u8).collect();
+        let region = Region::with_data(0x0800_c800, data.clone());
+        let options = McubootOptions {
+            header_size: 0x200,
+            version: parse_image_version("1.2.3+4").unwrap(),
+            load_addr: None,
+        };
+        let image = to_mcuboot_region(&region, &options).unwrap();
+        assert_eq!(image.address(), 0x0800_c600);
+        let bytes = image.data();
+        assert_eq!(bytes.len(), 0x200 + 100 + 40);
+        assert_eq!(bytes[0..4], [0x3d, 0xb8, 0xf3, 0x96]);
+        assert_eq!(bytes[8..10], [0x00, 0x02]);
+        assert_eq!(bytes[12..16], 100u32.to_le_bytes());
+        assert_eq!(bytes[20..28], [1, 2, 3, 0, 4, 0, 0, 0]);
+        assert_eq!(bytes[0x200..0x264], data[..]);
+
+        let tlv = &bytes[0x264..];
+        assert_eq!(tlv[0..8], [0x07, 0x69, 40, 0, 0x10, 0, 32, 0]);
+        assert_eq!(tlv[8..], Sha256::digest(&bytes[0..0x264])[..]);
+    }
+
+    #[test]
+    fn no_room_for_header() {
+        let region = Region::with_data(0x100, vec![0; 16]);
+        assert!(to_mcuboot_region(&region, &McubootOptions::default()).is_err());
+    }
+}
@@ -0,0 +1,468 @@
+use serde::Deserialize;
+use std::path::Path;
+
+use crate::error::Error;
+use crate::hexcrc::stm32_crc;
+use crate::patch::{apply_patches, Endian, Patch};
+use crate::range::{deserialize_u32, parse_number, AddressRange};
+use crate::region::Region;
+use crate::target::Target;
+
+/**
+ * Where the value of a metadata field comes from.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Source {
+    /// Fixed 'value', from the layout file (e.g., a magic number)
+    Const,
+    /// Firmware version, from the command-line
+    Version,
+    /// Number of image bytes that follow the metadata header
+    Length,
+    /// CRC32 of the image bytes that follow the metadata header
+    Crc32,
+    /// Build time, as seconds since the Unix epoch
+    Timestamp,
+    /// Git commit hash (bytes), from the command-line
+    GitHash,
+}
+
+/**
+ * Single field of the metadata header.
+ */
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Field {
+    name: String,
+    offset: usize,
+    size: usize,
+    source: Source,
+    #[serde(default)]
+    value: Option<u64>,
+    #[serde(default)]
+    endian: Option<Endian>,
+}
+
+/**
+ * Layout of the fixed-format metadata header that the bootloader expects at
+ * the start of the application, e.g.:
+ * ```toml
+ * address = 0x0800_c800
+ * size = 32
+ *
+ * [[fields]]
+ * name = "magic"
+ * offset = 0
+ * size = 4
+ * source = "const"
+ * value = 0x4c54_4657
+ * ```
+ */
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct MetadataLayout {
+    #[serde(default, deserialize_with = "deserialize_opt_u32")]
+    address: Option<u32>,
+    size: usize,
+    #[serde(default)]
+    endian: Endian,
+    fields: Vec<Field>,
+}
+
+/**
+ * Values for the metadata fields that come from the command-line, or from
+ * the build environment.
+ */
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MetadataValues {
+    pub version: Option<u64>,
+    pub timestamp: u64,
+    pub git_hash: Option<Vec<u8>>,
+}
+
+fn deserialize_opt_u32<'de, D>(deserializer: D) -> Result<Option<u32>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    deserialize_u32(deserializer).map(Some)
+}
+
+/**
+ * Parse a firmware version, given as either a number, or as
+ * 'MAJOR.MINOR.PATCH', which is packed as '(MAJOR << 24) | (MINOR << 16) |
+ * PATCH'.
+ */
+pub fn parse_version(arg: &str) -> Result<u64, String> {
+    let parts: Vec<&str> = arg.split('.').collect();
+    if parts.len() == 1 {
+        return parse_number(arg);
+    }
+    let num = |x: &str, max: u64| match x.parse::<u64>() {
+        Ok(n) if n <= max => Ok(n),
+        _ => Err(format!("invalid version '{}'", arg)),
+    };
+    match parts[..] {
+        [major, minor, patch] => {
+            Ok((num(major, 0xff)? << 24) | (num(minor, 0xff)? << 16) | num(patch, 0xffff)?)
+        }
+        _ => Err(format!("expected 'MAJOR.MINOR.PATCH', found '{}'", arg)),
+    }
+}
+
+/**
+ * Build time, using 'SOURCE_DATE_EPOCH' (for reproducible builds), if set.
+ */
+pub fn build_timestamp() -> u64 {
+    if let Some(epoch) = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|x| x.trim().parse::<u64>().ok())
+    {
+        return epoch;
+    }
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+impl MetadataLayout {
+    pub fn from_file(path: &Path) -> Result<Self, Error> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| Error::Metadata(format!("cannot read '{}' ({})", path.display(), e)))?;
+        Self::from_toml(&text)
+    }
+
+    pub fn from_toml(text: &str) -> Result<Self, Error> {
+        let layout: Self = toml::from_str(text).map_err(|e| Error::Metadata(format!("{}", e)))?;
+        for f in layout.fields.iter() {
+            if f.offset + f.size > layout.size {
+                return Err(Error::Metadata(format!(
+                    "field '{}' does not fit within the header (size = {})",
+                    f.name, layout.size
+                )));
+            }
+            let numeric = !matches!(f.source, Source::GitHash);
+            if numeric && ![1, 2, 4, 8].contains(&f.size) {
+                return Err(Error::Metadata(format!(
+                    "field '{}' must be 1, 2, 4, or 8 bytes",
+                    f.name
+                )));
+            }
+            if f.source == Source::Const && f.value.is_none() {
+                return Err(Error::Metadata(format!(
+                    "field '{}' needs a 'value'",
+                    f.name
+                )));
+            }
+        }
+        Ok(layout)
+    }
+
+    pub fn address(&self) -> Option<u32> {
+        self.address
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /**
+     * Bytes of a numeric field, which must be wide enough for the value.
+     */
+    fn encode(&self, field: &Field, value: u64) -> Result<Vec<u8>, Error> {
+        if field.size < 8 && value >> (8 * field.size) != 0 {
+            return Err(Error::Metadata(format!(
+                "value 0x{:x} does not fit within field '{}' ({} bytes)",
+                value, field.name, field.size
+            )));
+        }
+        Ok(match field.endian.unwrap_or(self.endian) {
+            Endian::Little => value.to_le_bytes()[0..field.size].to_vec(),
+            Endian::Big => value.to_be_bytes()[8 - field.size..].to_vec(),
+        })
+    }
+
+    /**
+     * Build the bytes of the header, given the length and CRC32 of the image
+     * data that follows it.
+     */
+    pub fn to_bytes(
+        &self,
+        values: &MetadataValues,
+        length: u64,
+        crc32: u32,
+    ) -> Result<Vec<u8>, Error> {
+        let mut header = vec![0; self.size];
+        for f in self.fields.iter() {
+            let missing = |opt: &str| {
+                Error::Metadata(format!("no value for field '{}' (see '{}')", f.name, opt))
+            };
+            let bytes = match f.source {
+                Source::Const => self.encode(f, f.value.unwrap())?,
+                Source::Version => {
+                    self.encode(f, values.version.ok_or(missing("--fw-version"))?)?
+                }
+                Source::Length => self.encode(f, length)?,
+                Source::Crc32 => self.encode(f, crc32 as u64)?,
+                Source::Timestamp => self.encode(f, values.timestamp)?,
+                Source::GitHash => {
+                    let mut hash = values.git_hash.clone().ok_or(missing("--git-hash"))?;
+                    if hash.len() > f.size {
+                        return Err(Error::Metadata(format!(
+                            "git hash ({} bytes) does not fit within field '{}' ({} bytes)",
+                            hash.len(),
+                            f.name,
+                            f.size
+                        )));
+                    }
+                    hash.resize(f.size, 0);
+                    hash
+                }
+            };
+            header[f.offset..f.offset + f.size].copy_from_slice(&bytes);
+        }
+        Ok(header)
+    }
+}
+
+/**
+ * Metadata header, its layout, and where it goes.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Metadata {
+    pub layout: MetadataLayout,
+    pub address: u32,
+    pub values: MetadataValues,
+    /// Allow the header to overwrite image data (e.g. a placeholder)
+    pub overwrite: bool,
+}
+
+impl Metadata {
+    /**
+     * Place the metadata header at its address, and fill it in. The length and
+     * CRC32 fields cover the image bytes that follow the header, up to the end
+     * of the (merged, and padded) image, which is everything that gets
+     * programmed after the header. The header must not overlap the image
+     * data, unless 'overwrite' is set.
+     */
+    pub fn apply(
+        &self,
+        regions: &mut Vec<Region>,
+        target: &Target,
+        append_crc: bool,
+    ) -> Result<(), Error> {
+        let size = self.layout.size() as u32;
+        let range = match self.address.checked_add(size) {
+            Some(end) => AddressRange::new(self.address, end),
+            None => {
+                return Err(Error::Metadata(format!(
+                    "header at 0x{:08x} (size = {}) exceeds 32-bit addresses",
+                    self.address, size
+                )))
+            }
+        };
+        let overlap: usize = regions
+            .iter()
+            .filter_map(|r| r.clip(&range))
+            .map(|r| r.len())
+            .sum();
+        if overlap > 0 && !self.overwrite {
+            return Err(Error::Metadata(format!(
+                "header at 0x{:08x} (size = {}) would overwrite {} bytes of image data (see '--metadata-overwrite')",
+                self.address, size, overlap
+            )));
+        }
+
+        // Reserve the header's space first (only where there is no data), so
+        // that it is covered by the image
+        let mut gaps: Vec<AddressRange> = Vec::new();
+        for addr in range.start()..range.end() {
+            if regions.iter().any(|r| r.range().contains(addr)) {
+                continue;
+            }
+            match gaps.last_mut() {
+                Some(g) if g.end() == addr => *g = AddressRange::new(g.start(), addr + 1),
+                _ => gaps.push(AddressRange::new(addr, addr + 1)),
+            }
+        }
+        let blanks: Vec<Patch> = gaps
+            .iter()
+            .map(|g| Patch::new(g.start(), vec![0; g.len()]))
+            .collect();
+        apply_patches(regions, &blanks, true)?;
+
+        let update = crate::make_update(regions, target, append_crc);
+        let image = update.to_vec();
+        let base = update
+            .packets()
+            .first()
+            .map(|p| p.address())
+            .unwrap_or(self.address);
+        let start = (self.address - base) as usize + self.layout.size();
+        let rest = &image[start.min(image.len())..];
+
+        let header = self
+            .layout
+            .to_bytes(&self.values, rest.len() as u64, stm32_crc(rest))?;
+        apply_patches(regions, &[Patch::new(self.address, header)], false)
+    }
+}
+
+//----------------------------------------------------------------------------
+// Tests
+//----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_version, Metadata, MetadataLayout, MetadataValues};
+    use crate::error::Error;
+    use crate::hexcrc::stm32_crc;
+    use crate::region::Region;
+
+    const LAYOUT: &str = r#"
+        size = 32
+
+        [[fields]]
+        name = "magic"
+        offset = 0
+        size = 4
+        source = "const"
+        value = 0x4c54_4657
+
+        [[fields]]
+        name = "version"
+        offset = 4
+        size = 4
+        source = "version"
+        endian = "big"
+
+        [[fields]]
+        name = "length"
+        offset = 8
+        size = 4
+        source = "length"
+
+        [[fields]]
+        name = "crc"
+        offset = 12
+        size = 4
+        source = "crc32"
+
+        [[fields]]
+        name = "timestamp"
+        offset = 16
+        size = 8
+        source = "timestamp"
+
+        [[fields]]
+        name = "git"
+        offset = 24
+        size = 8
+        source = "git-hash"
+    "#;
+
+    #[test]
+    fn parse_versions() {
+        assert_eq!(parse_version("1.2.3"), Ok(0x0102_0003));
+        assert_eq!(parse_version("0x0102"), Ok(0x0102));
+        assert!(parse_version("1.2").is_err());
+        assert!(parse_version("256.0.0").is_err());
+    }
+
+    #[test]
+    fn reject_fields_outside_header() {
+        let bad = LAYOUT.replace("offset = 24", "offset = 28");
+        assert!(MetadataLayout::from_toml(&bad).is_err());
+        let bad = LAYOUT.replace(
+            "offset = 4\n        size = 4",
+            "offset = 4\n        size = 3",
+        );
+        assert!(MetadataLayout::from_toml(&bad).is_err());
+    }
+
+    #[test]
+    fn fill_header_fields() {
+        let layout = MetadataLayout::from_toml(LAYOUT).unwrap();
+        let values = MetadataValues {
+            version: Some(0x0102_0003),
+            timestamp: 1_700_000_000,
+            git_hash: Some(vec![0xab, 0xcd, 0xef]),
+        };
+        let body: Vec<u8> = (0..100).map(|x| x as u8).collect();
+        let mut regions = vec![Region::with_data(0x0800_c820, body.clone())];
+        let mut metadata = Metadata {
+            layout,
+            address: 0x0800_c800,
+            values,
+            overwrite: false,
+        };
+        metadata
+            .apply(&mut regions, &Default::default(), true)
+            .unwrap();
+
+        let mono = Region::single_region(&regions).unwrap();
+        let mut header = vec![0x57, 0x46, 0x54, 0x4c, 0x01, 0x02, 0x00, 0x03];
+        header.extend(100u32.to_le_bytes());
+        header.extend(stm32_crc(&body).to_le_bytes());
+        header.extend(1_700_000_000u64.to_le_bytes());
+        header.extend([0xab, 0xcd, 0xef, 0, 0, 0, 0, 0]);
+        header.extend(body);
+        assert_eq!(mono, Region::with_data(0x0800_c800, header));
+
+        // The header is now part of the image
+        assert!(matches!(
+            metadata.apply(&mut regions, &Default::default(), true),
+            Err(Error::Metadata(_))
+        ));
+        metadata.overwrite = true;
+        assert!(metadata
+            .apply(&mut regions.clone(), &Default::default(), true)
+            .is_ok());
+        metadata.values = MetadataValues::default();
+        assert!(metadata
+            .apply(&mut regions, &Default::default(), true)
+            .is_err());
+    }
+
+    #[test]
+    fn reject_values_wider_than_fields() {
+        let values = MetadataValues {
+            version: Some(0x0102_0003),
+            timestamp: 1_700_000_000,
+            git_hash: Some(vec![0xab; 8]),
+        };
+        let layout = MetadataLayout::from_toml(LAYOUT).unwrap();
+        assert!(layout.to_bytes(&values, 100, 0).is_ok());
+
+        // Packed version, in a 2-byte field
+        let narrow = LAYOUT.replace(
+            "offset = 4\n        size = 4",
+            "offset = 4\n        size = 2",
+        );
+        let layout = MetadataLayout::from_toml(&narrow).unwrap();
+        assert!(matches!(
+            layout.to_bytes(&values, 100, 0),
+            Err(Error::Metadata(_))
+        ));
+
+        // 64-bit timestamp, in a 4-byte field
+        let narrow = LAYOUT.replace(
+            "offset = 16\n        size = 8",
+            "offset = 16\n        size = 4",
+        );
+        let layout = MetadataLayout::from_toml(&narrow).unwrap();
+        let late = MetadataValues {
+            timestamp: 1 << 32,
+            ..values.clone()
+        };
+        assert!(layout.to_bytes(&values, 100, 0).is_ok());
+        assert!(layout.to_bytes(&late, 100, 0).is_err());
+
+        // 20-byte (SHA-1) git hash, in an 8-byte field
+        let layout = MetadataLayout::from_toml(LAYOUT).unwrap();
+        let sha1 = MetadataValues {
+            git_hash: Some(vec![0xab; 20]),
+            ..values
+        };
+        assert!(layout.to_bytes(&sha1, 100, 0).is_err());
+    }
+}
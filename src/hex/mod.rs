@@ -1,19 +1,75 @@
-pub use append::{to_binary_file, to_include_file, to_include_text};
+pub use append::{to_binary_file, to_hex_file, to_include_text, to_packet_file};
 pub mod append;
+pub mod batch;
+pub use compress::Compression;
+pub mod compress;
+pub use config::Config;
+pub mod config;
+pub use delta::Delta;
+pub mod delta;
+pub use device::Device;
+pub mod device;
+pub mod diff;
+pub use erase::ErasePlan;
+pub mod erase;
+pub use digest::DigestAlg;
+pub mod digest;
+pub use encrypt::{CipherMode, Encryption};
+pub mod encrypt;
+pub use error::Error;
+pub mod error;
+pub mod header;
 pub mod hexcrc;
+pub mod input;
+pub mod map;
+pub use mcuboot::McubootOptions;
+pub mod mcuboot;
+pub use metadata::{Metadata, MetadataLayout, MetadataValues};
+pub mod metadata;
+pub use output::{OutputFormat, OutputSpec};
+pub mod output;
+pub use overlap::OverlapPolicy;
+pub mod overlap;
+pub use patch::{Endian, Patch};
+pub mod patch;
+pub use range::AddressRange;
+pub mod range;
 pub use region::*;
 pub mod region;
 pub use packet::*;
 pub mod packet;
+pub use sign::{SignAlg, Signer, SigningKey, VerifyingKey};
+pub mod sign;
+pub mod status;
+pub mod stdio;
+pub use summary::{Summary, SummaryFormat};
+pub mod summary;
+pub use target::Target;
+pub mod target;
 pub use update::*;
 pub mod update;
+pub mod verify;
+
+/**
+ * Merge the 'Region's, and then packetise them into a 'FirmwareUpdate'. The
+ * final packet is padded to the program granularity, unless a CRC is to be
+ * appended.
+ */
+pub fn make_update(regions: &[Region], target: &Target, append_crc: bool) -> FirmwareUpdate {
+    let regions = merge_regions(regions, target);
+    let packets = match Region::single_region(&regions) {
+        Some(r) => r.to_packets(target, !append_crc),
+        None => make_packets(&regions, target),
+    };
+    FirmwareUpdate::new(packets)
+}
 
 // OBSOLETE
-pub fn make_packets(regions: &mut [Region]) -> Vec<FirmwareUpdatePacket> {
+pub fn make_packets(regions: &[Region], target: &Target) -> Vec<FirmwareUpdatePacket> {
     let mut packets = Vec::new();
 
-    for r in regions.iter_mut() {
-        let mut fwups = r.to_packets(true);
+    for r in regions.iter() {
+        let mut fwups = r.to_packets(target, true);
         packets.append(&mut fwups);
     }
     packets
@@ -0,0 +1,276 @@
+use clap::ValueEnum;
+
+use crate::append::{to_binary_file, to_hex_file, to_include_file};
+use crate::digest::DigestAlg;
+use crate::region::Region;
+use crate::target::Target;
+use crate::update::FirmwareUpdate;
+
+/// Separator of the format and the path, in an output spec.
+const FORMAT_SEPARATOR: char = ':';
+
+/// Separator of the path and the per-output options, in an output spec.
+const OPTION_SEPARATOR: char = ',';
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// C include file
+    Include,
+    /// Raw binary file
+    Binary,
+    /// Intel HEX file
+    Hex,
+    /// Firmware update packets
+    Packets,
+}
+
+/**
+ * Formats of the outputs that hold the firmware image (i.e. all but the
+ * packets), which are written by 'Image::write(..)'.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    Include,
+    Binary,
+    Hex,
+}
+
+impl OutputFormat {
+    pub fn name(&self) -> &'static str {
+        match self {
+            OutputFormat::Include => "include",
+            OutputFormat::Binary => "binary",
+            OutputFormat::Hex => "hex",
+            OutputFormat::Packets => "packets",
+        }
+    }
+
+    /// Image format, unless the output is of the packets.
+    pub fn image(&self) -> Option<ImageFormat> {
+        match self {
+            OutputFormat::Include => Some(ImageFormat::Include),
+            OutputFormat::Binary => Some(ImageFormat::Binary),
+            OutputFormat::Hex => Some(ImageFormat::Hex),
+            OutputFormat::Packets => None,
+        }
+    }
+}
+
+/**
+ * An output file, with its own options (which override those of the
+ * command-line).
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutputSpec {
+    pub format: OutputFormat,
+    pub path: String,
+    /// Append the CRC32 ('crc'), or not ('nocrc'), else as '--append-crc'
+    pub append_crc: Option<bool>,
+    /// Prefix of the C constants ('prefix=NAME'), else as '--symbol-prefix'
+    pub symbol_prefix: Option<String>,
+}
+
+impl OutputSpec {
+    pub fn new(format: OutputFormat, path: &str) -> Self {
+        Self {
+            format,
+            path: path.to_string(),
+            append_crc: None,
+            symbol_prefix: None,
+        }
+    }
+}
+
+/**
+ * All of the outputs, in the order that they are written: those of the
+ * '--include', '--binary', '--hex', and '--packets' options (which use the
+ * command-line options), and then the 'extra' ones.
+ */
+pub fn output_specs(
+    include: &Option<String>,
+    binary: &Option<String>,
+    hex: &Option<String>,
+    packets: &Option<String>,
+    extra: &[OutputSpec],
+) -> Vec<OutputSpec> {
+    let mut specs: Vec<OutputSpec> = [
+        (OutputFormat::Include, include),
+        (OutputFormat::Binary, binary),
+        (OutputFormat::Hex, hex),
+        (OutputFormat::Packets, packets),
+    ]
+    .iter()
+    .filter_map(|(format, path)| path.as_deref().map(|x| OutputSpec::new(*format, x)))
+    .collect();
+    specs.extend(extra.iter().cloned());
+    specs
+}
+
+/**
+ * Digests of the firmware update, as appended to the binary output.
+ */
+pub fn digest_trailer(update: &FirmwareUpdate, digests: &[DigestAlg]) -> Vec<u8> {
+    digests.iter().flat_map(|x| update.digest(*x)).collect()
+}
+
+/**
+ * The firmware update, and what else the (include, binary, and HEX) outputs
+ * are built from.
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct Image<'a> {
+    pub update: &'a FirmwareUpdate,
+    /// Bytes that follow the CRC32 (if any) in the binary output
+    pub trailer: &'a [u8],
+    /// Merged 'Region's, for images with another CRC32 option
+    pub regions: &'a [Region],
+    pub target: &'a Target,
+    pub append_crc: bool,
+    pub digests: &'a [DigestAlg],
+    pub append_digest: bool,
+    pub symbol_prefix: &'a str,
+    /// Start linear address, for the HEX output
+    pub start: Option<u32>,
+}
+
+impl Image<'_> {
+    /**
+     * Firmware update for the other CRC32 option, and its trailer. The final
+     * packet is padded only without the CRC32, so the update (and its CRC32)
+     * may differ. (A signature is never part of the trailer, as signing is
+     * only done for '--append-crc'.)
+     */
+    pub fn variant(&self, append_crc: bool) -> (FirmwareUpdate, Vec<u8>) {
+        let update = crate::make_update(self.regions, self.target, append_crc);
+        let trailer = match self.append_digest {
+            true => digest_trailer(&update, self.digests),
+            false => Vec::new(),
+        };
+        (update, trailer)
+    }
+
+    /**
+     * Write an include, binary, or HEX output (as 'format', which is that of
+     * the spec), using the given firmware update (and trailer), with the
+     * options of the spec.
+     */
+    pub fn write(
+        &self,
+        format: ImageFormat,
+        spec: &OutputSpec,
+        update: &FirmwareUpdate,
+        trailer: &[u8],
+    ) {
+        let append_crc = spec.append_crc.unwrap_or(self.append_crc);
+        match format {
+            ImageFormat::Include => to_include_file(
+                &spec.path,
+                update,
+                append_crc,
+                self.digests,
+                spec.symbol_prefix.as_deref().unwrap_or(self.symbol_prefix),
+            ),
+            ImageFormat::Binary => {
+                to_binary_file(&spec.path, update, append_crc, trailer, self.target)
+            }
+            ImageFormat::Hex => to_hex_file(&spec.path, update, append_crc, self.start),
+        }
+    }
+}
+
+/**
+ * Parse an output spec, 'FORMAT:PATH[,OPTION...]', where the options are
+ * 'crc', 'nocrc' (for the include, binary, and HEX formats), and
+ * 'prefix=NAME' (for the include format), e.g.
+ * 'binary:app.bin,nocrc', or 'include:app.h,crc,prefix=kAppFirmware'.
+ */
+pub fn parse_output(arg: &str) -> Result<OutputSpec, String> {
+    let (format, rest) = arg
+        .split_once(FORMAT_SEPARATOR)
+        .ok_or_else(|| format!("expected FORMAT:PATH, not '{}'", arg))?;
+    let format = OutputFormat::from_str(format, true)?;
+    let mut parts = rest.split(OPTION_SEPARATOR);
+    let path = parts.next().unwrap_or_default();
+    if path.is_empty() {
+        return Err(format!("no path in '{}'", arg));
+    }
+    let mut spec = OutputSpec::new(format, path);
+    for option in parts {
+        match (option.split_once('='), format) {
+            (None, OutputFormat::Packets) if option == "crc" || option == "nocrc" => {
+                return Err("the packets hold no CRC32 ('crc' or 'nocrc')".to_string())
+            }
+            (None, _) if option == "crc" => spec.append_crc = Some(true),
+            (None, _) if option == "nocrc" => spec.append_crc = Some(false),
+            (Some(("prefix", name)), OutputFormat::Include) if !name.is_empty() => {
+                spec.symbol_prefix = Some(name.to_string())
+            }
+            (Some(("prefix", _)), OutputFormat::Include) => {
+                return Err(format!("no prefix in '{}'", arg))
+            }
+            (Some(("prefix", _)), _) => {
+                return Err("'prefix' is only for the include format".to_string())
+            }
+            _ => {
+                return Err(format!(
+                    "unknown option '{}' (expected: crc, nocrc, prefix=NAME)",
+                    option
+                ))
+            }
+        }
+    }
+    Ok(spec)
+}
+
+/**
+ * Split an output spec into its path, and the rest of the spec (before and
+ * after the path), so that the path can be rewritten.
+ */
+pub fn split_path(arg: &str) -> Option<(&str, &str, &str)> {
+    let (format, rest) = arg.split_once(FORMAT_SEPARATOR)?;
+    let end = rest.find(OPTION_SEPARATOR).unwrap_or(rest.len());
+    Some((&arg[..format.len() + 1], &rest[..end], &rest[end..]))
+}
+
+//----------------------------------------------------------------------------
+// Tests
+//----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_output, split_path, OutputFormat, OutputSpec};
+
+    #[test]
+    fn parse_output_specs() {
+        assert_eq!(
+            parse_output("binary:build/app.bin").unwrap(),
+            OutputSpec::new(OutputFormat::Binary, "build/app.bin")
+        );
+        let spec = parse_output("include:app.h,crc,prefix=kAppFirmware").unwrap();
+        assert_eq!(
+            (spec.format, spec.append_crc, spec.symbol_prefix.as_deref()),
+            (OutputFormat::Include, Some(true), Some("kAppFirmware"))
+        );
+        assert_eq!(parse_output("hex:-,nocrc").unwrap().append_crc, Some(false));
+
+        assert!(parse_output("app.bin").is_err());
+        assert!(parse_output("elf:app.elf").is_err());
+        assert!(parse_output("binary:").is_err());
+        assert!(parse_output("binary:app.bin,prefix=kApp").is_err());
+        assert!(parse_output("packets:app.pkt,crc").is_err());
+        assert!(parse_output("hex:app.hex,fill=0xff").is_err());
+    }
+
+    #[test]
+    fn split_output_path() {
+        assert_eq!(
+            split_path("include:Inc/app.h,crc"),
+            Some(("include:", "Inc/app.h", ",crc"))
+        );
+        assert_eq!(
+            split_path("binary:app.bin"),
+            Some(("binary:", "app.bin", ""))
+        );
+        assert_eq!(split_path("app.bin"), None);
+    }
+}
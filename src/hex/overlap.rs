@@ -0,0 +1,320 @@
+use clap::ValueEnum;
+use ihex::Record;
+
+use crate::error::Error;
+use crate::input::NumberedRecords;
+use crate::range::AddressRange;
+
+/**
+ * How to handle data records that (partially) overlap earlier records.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum OverlapPolicy {
+    /// Overlapping data is an error
+    #[default]
+    Error,
+    /// Keep the data of the record that appears first in the file
+    FirstWins,
+    /// Keep the data of the record that appears last in the file
+    LastWins,
+    /// Accept overlaps, but only if the overlapping bytes are identical
+    Identical,
+}
+
+/**
+ * Pair of data records, with their source line numbers and (absolute) address
+ * ranges, that overlap.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Overlap {
+    pub first_line: usize,
+    pub first: AddressRange,
+    pub second_line: usize,
+    pub second: AddressRange,
+    pub identical: bool,
+}
+
+impl Overlap {
+    pub fn to_error(&self) -> Error {
+        Error::Overlap {
+            first_line: self.first_line,
+            first: self.first,
+            second_line: self.second_line,
+            second: self.second,
+        }
+    }
+}
+
+/**
+ * Data record, at its absolute address.
+ */
+struct Chunk<'a> {
+    index: usize,
+    line: usize,
+    segment: u32,
+    base: u32,
+    range: AddressRange,
+    data: &'a [u8],
+}
+
+/**
+ * Compute the absolute addresses of the data records, using the same segment
+ * rules as 'Region::build_regions(..)'. Data that would extend past
+ * 0xffffffff is an error.
+ */
+fn data_chunks(records: &[(usize, Record)]) -> Result<Vec<Chunk<'_>>, Error> {
+    let mut chunks = Vec::new();
+    let mut segment: u32 = 0;
+    for (index, (line, r)) in records.iter().enumerate() {
+        match r {
+            Record::Data { offset, value } => {
+                if !value.is_empty() {
+                    let base = segment + *offset as u32;
+                    let range = AddressRange::with_size(base, value.len() as u32).ok_or(
+                        Error::AddressOverflow {
+                            address: base,
+                            size: value.len(),
+                            offset: 0,
+                        },
+                    )?;
+                    chunks.push(Chunk {
+                        index,
+                        line: *line,
+                        segment,
+                        base,
+                        range,
+                        data: value,
+                    });
+                }
+            }
+            Record::ExtendedSegmentAddress(base) => segment = (*base as u32) << 4,
+            Record::ExtendedLinearAddress(base) => segment = (*base as u32) << 16,
+            _ => segment = 0,
+        }
+    }
+    Ok(chunks)
+}
+
+/**
+ * Find all pairs of data records that overlap.
+ */
+pub fn find_overlaps(records: &[(usize, Record)]) -> Result<Vec<Overlap>, Error> {
+    let mut chunks = data_chunks(records)?;
+    chunks.sort_by_key(|c| (c.base, c.index));
+    let mut overlaps = Vec::new();
+
+    for (i, a) in chunks.iter().enumerate() {
+        let ra = a.range;
+        for b in chunks[i + 1..].iter() {
+            let rb = b.range;
+            let x = match ra.intersect(&rb) {
+                Some(x) => x,
+                None if rb.start() >= ra.end() => break,
+                None => continue,
+            };
+            let sa = (x.start() - a.base) as usize;
+            let sb = (x.start() - b.base) as usize;
+            let identical = a.data[sa..sa + x.len()] == b.data[sb..sb + x.len()];
+            let (first, second) = if a.index < b.index { (a, b) } else { (b, a) };
+            overlaps.push(Overlap {
+                first_line: first.line,
+                first: first.range,
+                second_line: second.line,
+                second: second.range,
+                identical,
+            });
+        }
+    }
+    overlaps.sort_by_key(|x| (x.first_line, x.second_line));
+    Ok(overlaps)
+}
+
+/**
+ * Remove the 'claimed' address ranges from the range, giving the (sorted)
+ * parts that are left over.
+ */
+fn subtract(range: AddressRange, claimed: &[AddressRange]) -> Vec<AddressRange> {
+    let mut parts = vec![range];
+    for c in claimed.iter().filter(|c| c.overlaps(&range)) {
+        parts = parts
+            .iter()
+            .flat_map(|p| {
+                let below = AddressRange::new(p.start(), c.start().clamp(p.start(), p.end()));
+                let above = AddressRange::new(c.end().clamp(p.start(), p.end()), p.end());
+                [below, above]
+            })
+            .filter(|p| p.len() > 0)
+            .collect();
+    }
+    parts
+}
+
+fn claim(claimed: &mut Vec<AddressRange>, range: AddressRange) {
+    claimed.push(range);
+    claimed.sort();
+    let mut merged: Vec<AddressRange> = Vec::with_capacity(claimed.len());
+    for r in claimed.iter() {
+        match merged.last_mut() {
+            Some(last) if r.start() <= last.end() => {
+                *last = AddressRange::new(last.start(), last.end().max(r.end()));
+            }
+            _ => merged.push(*r),
+        }
+    }
+    *claimed = merged;
+}
+
+/**
+ * Check the records for overlapping data, and apply the policy so that no
+ * overlaps remain, by trimming (or splitting) the data records that lose.
+ * Returns the updated records, and all overlaps that were found.
+ */
+pub fn resolve_overlaps(
+    records: &[(usize, Record)],
+    policy: OverlapPolicy,
+) -> Result<(NumberedRecords, Vec<Overlap>), Error> {
+    let overlaps = find_overlaps(records)?;
+    if overlaps.is_empty() {
+        return Ok((records.to_vec(), overlaps));
+    }
+    let mut chunks = data_chunks(records)?;
+    match policy {
+        OverlapPolicy::Error => return Err(overlaps[0].to_error()),
+        OverlapPolicy::Identical => {
+            if let Some(x) = overlaps.iter().find(|x| !x.identical) {
+                return Err(x.to_error());
+            }
+        }
+        OverlapPolicy::FirstWins => (),
+        OverlapPolicy::LastWins => chunks.reverse(),
+    }
+
+    // Records that win keep their data, and later records keep whatever parts
+    // are left over
+    let mut claimed: Vec<AddressRange> = Vec::new();
+    let mut trimmed: Vec<Option<Vec<Record>>> = vec![None; records.len()];
+    for c in chunks.iter() {
+        let range = c.range;
+        let parts = subtract(range, &claimed);
+        if parts != [range] {
+            let parts = parts
+                .iter()
+                .map(|p| {
+                    let start = (p.start() - c.base) as usize;
+                    Record::Data {
+                        offset: (p.start() - c.segment) as u16,
+                        value: c.data[start..start + p.len()].to_vec(),
+                    }
+                })
+                .collect();
+            trimmed[c.index] = Some(parts);
+        }
+        claim(&mut claimed, range);
+    }
+
+    let mut result = Vec::with_capacity(records.len());
+    for ((line, r), t) in records.iter().zip(trimmed) {
+        match t {
+            Some(parts) => result.extend(parts.into_iter().map(|p| (*line, p))),
+            None => result.push((*line, r.clone())),
+        }
+    }
+    Ok((result, overlaps))
+}
+
+//----------------------------------------------------------------------------
+// Tests
+//----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::{find_overlaps, resolve_overlaps, OverlapPolicy};
+    use crate::error::Error;
+    use crate::input::read_records;
+    use crate::region::Region;
+    use ihex::Record;
+
+    const OVERLAPPING: &str = ":020000040800F2
+:08C80000001122334455667754
+:04C8040044556677BA
+:04C80600AABBCCDD20
+:00000001FF
+";
+
+    fn build(records: &[(usize, Record)]) -> Region {
+        let mut records: Vec<Record> = records.iter().map(|(_, r)| r.clone()).collect();
+        let regions = Region::build_regions(&mut records);
+        Region::single_region(&regions).unwrap()
+    }
+
+    #[test]
+    fn report_overlapping_lines() {
+        let records = read_records(OVERLAPPING);
+        let overlaps = find_overlaps(&records).unwrap();
+        assert_eq!(overlaps.len(), 3);
+        assert_eq!((overlaps[0].first_line, overlaps[0].second_line), (2, 3));
+        assert!(overlaps[0].identical);
+        assert_eq!((overlaps[1].first_line, overlaps[1].second_line), (2, 4));
+        assert!(!overlaps[1].identical);
+        assert_eq!(overlaps[2].first.start(), 0x0800_c804);
+
+        let err = resolve_overlaps(&records, OverlapPolicy::Error).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::Overlap {
+                first_line: 2,
+                second_line: 3,
+                ..
+            }
+        ));
+        assert!(resolve_overlaps(&records, OverlapPolicy::Identical).is_err());
+    }
+
+    #[test]
+    fn first_and_last_wins() {
+        let records = read_records(OVERLAPPING);
+        let (first, _) = resolve_overlaps(&records, OverlapPolicy::FirstWins).unwrap();
+        assert!(find_overlaps(&first).unwrap().is_empty());
+        let region = build(&first);
+        assert_eq!(region.address(), 0x0800_c800);
+        assert_eq!(region.len(), 10);
+        let bytes: Vec<u8> = region.to_packets(&Default::default(), false)[0].to_vec();
+        assert_eq!(
+            bytes,
+            [0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0xcc, 0xdd]
+        );
+
+        let (last, _) = resolve_overlaps(&records, OverlapPolicy::LastWins).unwrap();
+        assert!(find_overlaps(&last).unwrap().is_empty());
+        let region = build(&last);
+        let bytes: Vec<u8> = region.to_packets(&Default::default(), false)[0].to_vec();
+        assert_eq!(
+            bytes,
+            [0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0xaa, 0xbb, 0xcc, 0xdd]
+        );
+    }
+
+    #[test]
+    fn accept_identical_duplicates() {
+        let records = read_records(OVERLAPPING);
+        let records: Vec<(usize, Record)> = records.into_iter().filter(|(n, _)| *n != 4).collect();
+        let (result, overlaps) = resolve_overlaps(&records, OverlapPolicy::Identical).unwrap();
+        assert_eq!(overlaps.len(), 1);
+        assert_eq!(build(&result).len(), 8);
+    }
+
+    #[test]
+    fn reject_data_past_4gb() {
+        let top = ":02000004FFFFFC\n:10FFF000000102030405060708090A0B0C0D0E0F89\n:00000001FF\n";
+        let records = read_records(top);
+        assert_eq!(records.len(), 3);
+        assert!(matches!(
+            resolve_overlaps(&records, OverlapPolicy::Error),
+            Err(Error::AddressOverflow {
+                address: 0xffff_fff0,
+                size: 16,
+                ..
+            })
+        ));
+    }
+}
@@ -3,11 +3,17 @@ use serde_big_array::BigArray;
 
 use crate::hexcrc::calc_ccitt_crc;
 
-// Must be divisible by 8 (bytes), for the 'HAL_FLASH_Program(..)' routine.
+// Must be divisible by 8 (bytes), for the 'HAL_FLASH_Program(..)' routine;
+// see 'Target::packet_size()' for coarser program granularities.
 pub const MAX_DATA_LENGTH: usize = 200;
 
+// Size of a packet, as sent over USB.
+pub const PACKET_SIZE: usize = std::mem::size_of::<FirmwareUpdatePacket>();
+
 /**
- * Packet format for sending firmware updates, via USB.
+ * Packet format for sending firmware updates, via USB. The address and the
+ * CRC16 are big-endian (byte-swapped) on the wire, whereas the values within
+ * the data of the command packets ('*e' and '*z') are little-endian.
  */
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 #[repr(C, packed)] // Keep the field order & packing (very important)
@@ -44,6 +50,59 @@ impl FirmwareUpdatePacket {
         }
     }
 
+    /**
+     * Erase-command packet, with the address of the sector to erase (which is
+     * big-endian, as for all packets), and the 32-bit size of the sector as
+     * its data (which is little-endian, as for all values within the data).
+     */
+    pub fn erase(addr: u32, size: u32) -> Self {
+        let mut data: [u8; MAX_DATA_LENGTH] = [0; MAX_DATA_LENGTH];
+        data[0..4].copy_from_slice(&size.to_le_bytes());
+        let mut packet = Self::new(addr, data, 4);
+        packet.update_char = b'e';
+        packet
+    }
+
+    /**
+     * Header packet for a compressed image, with the (little-endian) format,
+     * image length and CRC32, and compressed length, as its data.
+     */
+    pub fn compression_header(
+        addr: u32,
+        format: u8,
+        length: u32,
+        crc32: u32,
+        compressed: u32,
+    ) -> Self {
+        let mut data: [u8; MAX_DATA_LENGTH] = [0; MAX_DATA_LENGTH];
+        data[0] = format;
+        data[4..8].copy_from_slice(&length.to_le_bytes());
+        data[8..12].copy_from_slice(&crc32.to_le_bytes());
+        data[12..16].copy_from_slice(&compressed.to_le_bytes());
+        let mut packet = Self::new(addr, data, 16);
+        packet.update_char = b'z';
+        packet
+    }
+
+    /**
+     * The same data, as a compressed-data ('*c') packet, whose address is the
+     * offset of its data within the compressed stream (rather than a flash
+     * address), so that a bootloader that does not support compression will
+     * not program it.
+     */
+    pub fn into_compressed(mut self) -> Self {
+        self.update_char = b'c';
+        self
+    }
+
+    /**
+     * Packet kind: 'u' (data), 'e' (erase), 'z' (compression header), or 'c'
+     * (compressed data).
+     */
+    pub fn kind(&self) -> char {
+        self.update_char as char
+    }
+
     pub fn address(&self) -> u32 {
         self.address.swap_bytes()
     }
@@ -60,6 +119,25 @@ impl FirmwareUpdatePacket {
         let len = self.data_length as usize;
         self.data[0..len].to_vec()
     }
+
+    /**
+     * Packed (in-memory) representation of the packet, as sent over USB.
+     */
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(PACKET_SIZE);
+        bytes.push(self.boot_char);
+        bytes.push(self.update_char);
+        bytes.extend(self._dummy1.to_ne_bytes());
+        bytes.extend(self.address.to_ne_bytes());
+        bytes.push(self.data_length);
+        bytes.push(self._dummy2);
+        bytes.extend(self.data_crc.to_ne_bytes());
+        bytes.extend(self.data);
+        bytes.push(self.end_of_packet);
+        bytes.extend(self._dummy3.to_ne_bytes());
+        bytes.push(self._dummy4);
+        bytes
+    }
 }
 
 //----------------------------------------------------------------------------
@@ -69,14 +147,14 @@ impl FirmwareUpdatePacket {
 #[cfg(test)]
 mod tests {
     use crate::hexcrc::calc_ccitt_crc;
-    use crate::packet::{FirmwareUpdatePacket, MAX_DATA_LENGTH};
+    use crate::packet::{FirmwareUpdatePacket, MAX_DATA_LENGTH, PACKET_SIZE};
 
     const TEST_ADDR: u32 = 0x0800c8000;
 
     fn make_test_data() -> [u8; MAX_DATA_LENGTH] {
         let mut test_data: [u8; MAX_DATA_LENGTH] = [0; MAX_DATA_LENGTH];
-        for i in 0..MAX_DATA_LENGTH {
-            test_data[i] = rand::random::<u8>();
+        for x in test_data.iter_mut() {
+            *x = rand::random::<u8>();
         }
         test_data
     }
@@ -89,4 +167,17 @@ mod tests {
         assert!(crc16 == packt.crc16());
         assert!(TEST_ADDR == packt.address());
     }
+
+    #[test]
+    fn packed_bytes_match_layout() {
+        let tdata = make_test_data();
+        let packt = FirmwareUpdatePacket::new(TEST_ADDR, tdata, 8);
+        let bytes = packt.to_bytes();
+        assert_eq!(bytes.len(), PACKET_SIZE);
+        assert_eq!(&bytes[0..2], b"*u");
+        assert_eq!(&bytes[4..8], &TEST_ADDR.to_be_bytes());
+        assert_eq!(bytes[8], 8);
+        assert_eq!(&bytes[12..12 + MAX_DATA_LENGTH], &tdata);
+        assert_eq!(bytes[12 + MAX_DATA_LENGTH], b'\n');
+    }
 }
@@ -0,0 +1,274 @@
+use clap::ValueEnum;
+use serde::Deserialize;
+use std::path::Path;
+
+use crate::error::Error;
+use crate::range::{parse_address, parse_number};
+use crate::region::Region;
+
+/**
+ * Byte-order of multi-byte values written by patches.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Endian {
+    #[default]
+    Little,
+    Big,
+}
+
+/**
+ * Bytes to write into the firmware image, at the given address.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Patch {
+    address: u32,
+    bytes: Vec<u8>,
+}
+
+impl Patch {
+    pub fn new(address: u32, bytes: Vec<u8>) -> Self {
+        Self { address, bytes }
+    }
+
+    pub fn u32(address: u32, value: u32, endian: Endian) -> Self {
+        let bytes = match endian {
+            Endian::Little => value.to_le_bytes(),
+            Endian::Big => value.to_be_bytes(),
+        };
+        Self::new(address, bytes.to_vec())
+    }
+
+    pub fn address(&self) -> u32 {
+        self.address
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+}
+
+/**
+ * Parse a string of hex-digit pairs, optionally separated by spaces, ':', or
+ * '-'; e.g. "DEADBEEF", or "de:ad:be:ef".
+ */
+pub fn parse_hex_bytes(arg: &str) -> Result<Vec<u8>, String> {
+    let digits: String = arg
+        .trim()
+        .trim_start_matches("0x")
+        .chars()
+        .filter(|c| !matches!(c, ' ' | ':' | '-' | '_'))
+        .collect();
+    if digits.is_empty() || !digits.len().is_multiple_of(2) {
+        return Err(format!("expected pairs of hex digits, found '{}'", arg));
+    }
+    if !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("invalid hex bytes '{}'", arg));
+    }
+    (0..digits.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&digits[i..i + 2], 16)
+                .map_err(|_| format!("invalid hex bytes '{}'", arg))
+        })
+        .collect()
+}
+
+fn split_assignment(arg: &str) -> Result<(u32, &str), String> {
+    let (addr, value) = arg
+        .split_once('=')
+        .ok_or(format!("expected 'ADDR=VALUE', found '{}'", arg))?;
+    Ok((parse_address(addr)?, value))
+}
+
+/**
+ * Parse a 'ADDR=HEX-BYTES' patch, from the command-line.
+ */
+pub fn parse_bytes_patch(arg: &str) -> Result<Patch, String> {
+    let (addr, value) = split_assignment(arg)?;
+    Ok(Patch::new(addr, parse_hex_bytes(value)?))
+}
+
+/**
+ * Parse a 'ADDR=VALUE' 32-bit patch, from the command-line; the byte-order is
+ * applied later.
+ */
+pub fn parse_u32_patch(arg: &str) -> Result<(u32, u32), String> {
+    let (addr, value) = split_assignment(arg)?;
+    let value = parse_number(value)?;
+    let value = u32::try_from(value).map_err(|_| format!("value '{}' exceeds 32 bits", arg))?;
+    Ok((addr, value))
+}
+
+/**
+ * Read patches from a file, with one patch per line, using the same syntax as
+ * the command-line options:
+ * ```text
+ * # Serial number, and calibration constants
+ * set-u32 0x0801f800=1234
+ * set     0x0801f804=DEADBEEF
+ * ```
+ */
+pub fn read_patch_file(path: &Path, endian: Endian) -> Result<Vec<Patch>, Error> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| Error::Patch(format!("cannot read '{}' ({})", path.display(), e)))?;
+    let mut patches = Vec::new();
+    for (num, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let err = |e: String| Error::Patch(format!("{}:{}: {}", path.display(), num + 1, e));
+        let patch = match line.split_once(char::is_whitespace) {
+            Some(("set", arg)) => parse_bytes_patch(arg.trim()).map_err(err)?,
+            Some(("set-u32", arg)) => {
+                let (addr, value) = parse_u32_patch(arg.trim()).map_err(err)?;
+                Patch::u32(addr, value, endian)
+            }
+            _ => return Err(err(format!("unknown patch '{}'", line))),
+        };
+        patches.push(patch);
+    }
+    Ok(patches)
+}
+
+/**
+ * Gather the patches from the command-line options, and then from the patch
+ * file (if any).
+ */
+pub fn collect_patches(
+    set: &[Patch],
+    set_u32: &[(u32, u32)],
+    patch_file: &Option<String>,
+    endian: Endian,
+) -> Result<Vec<Patch>, Error> {
+    let mut patches = set.to_vec();
+    for (address, value) in set_u32.iter() {
+        patches.push(Patch::u32(*address, *value, endian));
+    }
+    if let Some(path) = patch_file {
+        patches.extend(read_patch_file(Path::new(path), endian)?);
+    }
+    Ok(patches)
+}
+
+/**
+ * Write the patches into the 'Region's. All patched bytes must already be
+ * covered by 'Region's, unless 'extend' is set, and then new 'Region's are
+ * added for the uncovered bytes.
+ */
+pub fn apply_patches(
+    regions: &mut Vec<Region>,
+    patches: &[Patch],
+    extend: bool,
+) -> Result<(), Error> {
+    for p in patches.iter() {
+        let err = Error::NotCovered {
+            address: p.address,
+            size: p.len(),
+        };
+        let end = p.address.checked_add(p.len() as u32).ok_or(err.clone())?;
+        let covered = |a: u32| regions.iter().any(|r| r.range().contains(a));
+        if !extend && !(p.address..end).all(covered) {
+            return Err(err);
+        }
+
+        let mut fresh: Option<Region> = None;
+        for (addr, x) in (p.address..end).zip(p.bytes.iter()) {
+            if let Some(r) = regions.iter_mut().find(|r| r.range().contains(addr)) {
+                r.write(addr, &[*x]);
+                continue;
+            }
+            match &mut fresh {
+                Some(r) if r.range().end() == addr => r.extend(&[*x]),
+                _ => {
+                    regions.extend(fresh.take());
+                    fresh = Some(Region::with_data(addr, vec![*x]));
+                }
+            }
+        }
+        regions.extend(fresh);
+    }
+    regions.sort();
+    Ok(())
+}
+
+//----------------------------------------------------------------------------
+// Tests
+//----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        apply_patches, parse_bytes_patch, parse_hex_bytes, parse_u32_patch, Endian, Patch,
+    };
+    use crate::error::Error;
+    use crate::region::Region;
+
+    #[test]
+    fn parse_patches() {
+        assert_eq!(
+            parse_hex_bytes("de:ad:BE:ef"),
+            Ok(vec![0xde, 0xad, 0xbe, 0xef])
+        );
+        assert!(parse_hex_bytes("abc").is_err());
+        assert!(parse_hex_bytes("aéa").is_err());
+        assert!(parse_hex_bytes("+1").is_err());
+        assert_eq!(
+            parse_bytes_patch("0x0801f800=0102"),
+            Ok(Patch::new(0x0801_f800, vec![1, 2]))
+        );
+        assert_eq!(
+            parse_u32_patch("0x100=0x12345678"),
+            Ok((0x100, 0x1234_5678))
+        );
+        assert_eq!(
+            Patch::u32(0x100, 0x1234_5678, Endian::Big).bytes(),
+            &[0x12, 0x34, 0x56, 0x78]
+        );
+    }
+
+    #[test]
+    fn patch_covered_bytes_only() {
+        let mut regions = vec![Region::with_data(0x1000, vec![0; 0x10])];
+        let patches = [Patch::u32(0x1004, 0x1234_5678, Endian::Little)];
+        apply_patches(&mut regions, &patches, false).unwrap();
+        let mut expect = vec![0; 0x10];
+        expect[4..8].copy_from_slice(&[0x78, 0x56, 0x34, 0x12]);
+        assert_eq!(regions, [Region::with_data(0x1000, expect.clone())]);
+
+        let patches = [Patch::new(0x100e, vec![0xaa; 4])];
+        assert!(matches!(
+            apply_patches(&mut regions, &patches, false),
+            Err(Error::NotCovered {
+                address: 0x100e,
+                size: 4
+            })
+        ));
+        assert_eq!(regions, [Region::with_data(0x1000, expect)]);
+    }
+
+    #[test]
+    fn patch_and_extend() {
+        let mut regions = vec![Region::with_data(0x1000, vec![0; 0x10])];
+        let patches = [
+            Patch::new(0x100e, vec![0xaa; 4]),
+            Patch::new(0x2000, vec![1]),
+        ];
+        apply_patches(&mut regions, &patches, true).unwrap();
+        let mut expect = vec![0; 0x10];
+        expect[14..].copy_from_slice(&[0xaa, 0xaa]);
+        assert_eq!(
+            regions,
+            [
+                Region::with_data(0x1000, expect),
+                Region::with_data(0x1010, vec![0xaa, 0xaa]),
+                Region::with_data(0x2000, vec![1]),
+            ]
+        );
+    }
+}
@@ -0,0 +1,188 @@
+use serde::{Deserialize, Deserializer};
+use std::fmt;
+
+/**
+ * Half-open range of (32-bit) addresses: 'start <= addr < end'.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct AddressRange {
+    start: u32,
+    end: u32,
+}
+
+impl fmt::Display for AddressRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "0x{:08x}..0x{:08x}", self.start, self.end)
+    }
+}
+
+impl AddressRange {
+    pub fn new(start: u32, end: u32) -> Self {
+        assert!(start <= end);
+        Self { start, end }
+    }
+
+    /**
+     * Range of 'size' bytes from 'start', if it ends at, or below, 0xffffffff.
+     */
+    pub fn with_size(start: u32, size: u32) -> Option<Self> {
+        Some(Self::new(start, start.checked_add(size)?))
+    }
+
+    pub fn start(&self) -> u32 {
+        self.start
+    }
+
+    pub fn end(&self) -> u32 {
+        self.end
+    }
+
+    pub fn len(&self) -> usize {
+        (self.end - self.start) as usize
+    }
+
+    pub fn contains(&self, addr: u32) -> bool {
+        self.start <= addr && addr < self.end
+    }
+
+    pub fn overlaps(&self, other: &AddressRange) -> bool {
+        self.start < other.end && other.start < self.end
+    }
+
+    pub fn intersect(&self, other: &AddressRange) -> Option<AddressRange> {
+        if self.overlaps(other) {
+            Some(Self::new(
+                self.start.max(other.start),
+                self.end.min(other.end),
+            ))
+        } else {
+            None
+        }
+    }
+}
+
+/**
+ * Parse a number in decimal, hexadecimal ('0x' prefix), or binary ('0b'
+ * prefix), and with optional '_' digit separators.
+ */
+pub fn parse_number(arg: &str) -> Result<u64, String> {
+    let arg = arg.trim().replace('_', "");
+    let res = if let Some(hex) = arg.strip_prefix("0x").or(arg.strip_prefix("0X")) {
+        u64::from_str_radix(hex, 16)
+    } else if let Some(bin) = arg.strip_prefix("0b") {
+        u64::from_str_radix(bin, 2)
+    } else {
+        arg.parse::<u64>()
+    };
+    res.map_err(|e| format!("invalid number '{}' ({})", arg, e))
+}
+
+pub fn parse_address(arg: &str) -> Result<u32, String> {
+    let value = parse_number(arg)?;
+    u32::try_from(value).map_err(|_| format!("address '{}' exceeds 32 bits", arg))
+}
+
+/**
+ * Parse a signed address offset, e.g. '+0x40000' or '-0x40000'.
+ */
+pub fn parse_offset(arg: &str) -> Result<i64, String> {
+    let arg = arg.trim();
+    let (neg, value) = match arg.strip_prefix('-') {
+        Some(x) => (true, parse_number(x)?),
+        None => (false, parse_number(arg.strip_prefix('+').unwrap_or(arg))?),
+    };
+    if value > u32::MAX as u64 {
+        return Err(format!("offset '{}' exceeds 32 bits", arg));
+    }
+    Ok(if neg { -(value as i64) } else { value as i64 })
+}
+
+/**
+ * Parse an address range from the command-line, given as either 'START..END'
+ * (end-exclusive), or as 'START+SIZE'.
+ */
+pub fn parse_range(arg: &str) -> Result<AddressRange, String> {
+    let (start, end) = if let Some((start, end)) = arg.split_once("..") {
+        (parse_number(start)?, parse_number(end)?)
+    } else if let Some((start, size)) = arg.split_once('+') {
+        let start = parse_number(start)?;
+        let end = start.checked_add(parse_number(size)?);
+        (
+            start,
+            end.ok_or(format!("range '{}' exceeds 32 bits", arg))?,
+        )
+    } else {
+        return Err(format!(
+            "expected 'START..END' or 'START+SIZE', found '{}'",
+            arg
+        ));
+    };
+    if start > end || end > 1 << 32 {
+        return Err(format!("invalid address range '{}'", arg));
+    }
+    let end = u32::try_from(end).map_err(|_| format!("range '{}' exceeds 32 bits", arg))?;
+    Ok(AddressRange::new(start as u32, end))
+}
+
+/**
+ * Addresses and sizes, within device-profile files, can be given as integers,
+ * or as strings (for hexadecimal values in JSON).
+ */
+pub(crate) fn deserialize_u32<'de, D>(deserializer: D) -> Result<u32, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Value {
+        Int(u64),
+        Str(String),
+    }
+    let value = match Value::deserialize(deserializer)? {
+        Value::Int(x) => x,
+        Value::Str(s) => parse_number(&s).map_err(serde::de::Error::custom)?,
+    };
+    u32::try_from(value).map_err(|_| serde::de::Error::custom("value exceeds 32 bits"))
+}
+
+//----------------------------------------------------------------------------
+// Tests
+//----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_address, parse_offset, parse_range, AddressRange};
+
+    #[test]
+    fn parse_ranges_and_addresses() {
+        assert_eq!(parse_address("0x0800_c800"), Ok(0x0800c800));
+        assert_eq!(parse_address("4096"), Ok(4096));
+        assert!(parse_address("0x1_0000_0000").is_err());
+        assert_eq!(parse_offset("-0x4_0000"), Ok(-0x40000));
+        assert_eq!(parse_offset("+0x4_0000"), Ok(0x40000));
+        assert_eq!(parse_offset("16"), Ok(16));
+        assert!(parse_offset("-0x1_0000_0000").is_err());
+        assert_eq!(
+            parse_range("0x08000000..0x08010000"),
+            Ok(AddressRange::new(0x08000000, 0x08010000))
+        );
+        assert_eq!(
+            parse_range("0x0801f800+0x800"),
+            Ok(AddressRange::new(0x0801f800, 0x08020000))
+        );
+        assert!(parse_range("0x2000..0x1000").is_err());
+        assert!(parse_range("0x2000").is_err());
+        assert!(parse_range("0xffffffffffffffff+1").is_err());
+        assert!(parse_range("0xffffffff+2").is_err());
+    }
+
+    #[test]
+    fn range_intersections() {
+        let a = AddressRange::new(0x100, 0x200);
+        let b = AddressRange::new(0x180, 0x280);
+        let c = AddressRange::new(0x200, 0x300);
+        assert_eq!(a.intersect(&b), Some(AddressRange::new(0x180, 0x200)));
+        assert!(!a.overlaps(&c));
+        assert_eq!(a.intersect(&c), None);
+    }
+}
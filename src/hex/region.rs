@@ -1,7 +1,10 @@
 use ihex::Record;
 use std::cmp::Ordering;
 
+use crate::error::Error;
 use crate::packet::{FirmwareUpdatePacket, MAX_DATA_LENGTH};
+use crate::range::AddressRange;
+use crate::target::Target;
 
 /**
  * Represents a single contiguous region of 'u8' values, read from a HEX file.
@@ -32,6 +35,10 @@ impl Region {
         }
     }
 
+    pub fn with_data(base: u32, data: Vec<u8>) -> Self {
+        Self { base, data }
+    }
+
     pub fn len(&self) -> usize {
         self.data.len()
     }
@@ -40,10 +47,86 @@ impl Region {
         self.data.is_empty()
     }
 
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
     pub fn address(&self) -> u32 {
         self.base
     }
 
+    /**
+     * Address range of the data. A 'Region' always ends at, or below,
+     * 0xffffffff, as HEX data past that is rejected when it is read (and
+     * moving, or patching, data checks the same).
+     */
+    pub fn range(&self) -> AddressRange {
+        let end = self.base as u64 + self.data.len() as u64;
+        AddressRange::new(self.base, u32::try_from(end).unwrap_or(u32::MAX))
+    }
+
+    pub fn extend(&mut self, data: &[u8]) {
+        self.data.extend(data);
+    }
+
+    /**
+     * Overwrite the bytes at 'address', which must lie within the 'Region'.
+     */
+    pub fn write(&mut self, address: u32, bytes: &[u8]) {
+        let start = (address - self.base) as usize;
+        self.data[start..start + bytes.len()].copy_from_slice(bytes);
+    }
+
+    /**
+     * Copy of the 'Region', with its base address moved by a signed offset.
+     */
+    pub fn offset(&self, offset: i64) -> Result<Region, Error> {
+        let base = self.base as i64 + offset;
+        let end = base + self.data.len() as i64;
+        if base < 0 || end > u32::MAX as i64 {
+            return Err(Error::AddressOverflow {
+                address: self.base,
+                size: self.data.len(),
+                offset,
+            });
+        }
+        Ok(Region {
+            base: base as u32,
+            data: self.data.clone(),
+        })
+    }
+
+    /**
+     * Copy of the part of the 'Region' that lies within the address range.
+     */
+    pub fn clip(&self, range: &AddressRange) -> Option<Region> {
+        let r = self.range().intersect(range)?;
+        let start = (r.start() - self.base) as usize;
+        let end = (r.end() - self.base) as usize;
+        Some(Region {
+            base: r.start(),
+            data: self.data[start..end].to_vec(),
+        })
+    }
+
+    /**
+     * Remove the address range from the 'Region', splitting it in two if the
+     * range lies strictly inside of it.
+     */
+    pub fn exclude(&self, range: &AddressRange) -> Vec<Region> {
+        let full = self.range();
+        if !full.overlaps(range) {
+            return vec![self.clone()];
+        }
+        let below = AddressRange::new(full.start(), range.start().max(full.start()));
+        let above = AddressRange::new(range.end().min(full.end()), full.end());
+        [below, above]
+            .iter()
+            .filter(|r| r.len() > 0)
+            .filter_map(|r| self.clip(r))
+            .collect()
+    }
+
     /**
      * Build an array of (upto) 64 kB "regions" of firmware (binary-)data.
      */
@@ -120,15 +203,11 @@ impl Region {
         };
         let mut last = mono.base as usize + mono.data.len();
 
-        loop {
-            let mut curr = if let Some(curr) = iter.next() {
-                curr.clone()
-            } else {
-                // No more 'Region's
-                break;
-            };
+        for curr in iter {
+            let mut curr = curr.clone();
             let next = curr.base as usize;
-            let npad = next - last;
+            // Overlaps must have been resolved, see 'overlap::resolve_overlaps(..)'
+            let npad = next.checked_sub(last).expect("overlapping 'Region's");
             let mut pads = vec![0; npad];
             mono.data.append(&mut pads);
             last += npad + curr.data.len();
@@ -137,42 +216,45 @@ impl Region {
         Some(mono)
     }
 
-    pub fn to_packets(&mut self, align64: bool) -> Vec<FirmwareUpdatePacket> {
+    /**
+     * Split the 'Region' into packets, each sized so that packet addresses stay
+     * aligned to the program granularity of the 'Target'. The final packet is
+     * padded to the granularity, if 'align' is set.
+     */
+    pub fn to_packets(&self, target: &Target, align: bool) -> Vec<FirmwareUpdatePacket> {
         let mut packets = Vec::new();
         let mut addr = self.base;
-        let mut iter = self.data.chunks_exact(MAX_DATA_LENGTH);
-
-        loop {
-            if let Some(c) = iter.next() {
-                let data: [u8; MAX_DATA_LENGTH] = c.try_into().unwrap();
-                let fwup = FirmwareUpdatePacket::new(addr, data, MAX_DATA_LENGTH);
-                packets.push(fwup);
-                addr += MAX_DATA_LENGTH as u32;
-            } else {
-                // Pad to 8-byte-aligned sizes, for STM32G4xx
-                let mut last = Vec::with_capacity(MAX_DATA_LENGTH);
-                last.extend(iter.remainder());
-                if align64 {
-                    let mut pads = vec![0; last.len() & 0x07];
-                    last.append(&mut pads);
-                    assert!(last.len() & 0x7 == 0);
-                }
-                let size = last.len();
-                if size > 0 {
-                    last.resize(MAX_DATA_LENGTH, 0);
-                    let data: [u8; MAX_DATA_LENGTH] = last.try_into().unwrap();
-                    let fwup = FirmwareUpdatePacket::new(addr, data, size);
-                    packets.push(fwup);
-                }
-                break;
-            }
+        let size = target.packet_size();
+        let mut iter = self.data.chunks_exact(size);
+
+        for c in iter.by_ref() {
+            let mut data: [u8; MAX_DATA_LENGTH] = [0; MAX_DATA_LENGTH];
+            data[0..size].copy_from_slice(c);
+            let fwup = FirmwareUpdatePacket::new(addr, data, size);
+            packets.push(fwup);
+            addr += size as u32;
+        }
+
+        // Pad to the program granularity (8-byte-aligned sizes, for STM32G4xx)
+        let mut last = Vec::with_capacity(MAX_DATA_LENGTH);
+        last.extend(iter.remainder());
+        if align {
+            last.resize(target.align_up(last.len()), 0);
+            assert!(target.is_aligned(last.len()));
+        }
+        let size = last.len();
+        if size > 0 {
+            last.resize(MAX_DATA_LENGTH, 0);
+            let data: [u8; MAX_DATA_LENGTH] = last.try_into().unwrap();
+            let fwup = FirmwareUpdatePacket::new(addr, data, size);
+            packets.push(fwup);
         }
         packets
     }
 }
 
 // OBSOLETE
-pub fn merge_regions(regions: &[Region]) -> Vec<Region> {
+pub fn merge_regions(regions: &[Region], target: &Target) -> Vec<Region> {
     let mut result: Vec<Region> = Vec::new();
     let mut iter = regions.iter();
 
@@ -194,15 +276,15 @@ pub fn merge_regions(regions: &[Region]) -> Vec<Region> {
         // Compute the index of the last byte of the previous 'Region'
         let last = prev.base as usize + prev.data.len() - 1;
 
-        // Compute the index of the start of the next 'u64'-aligned chunk, if
-        // 'Region's are contiguous
-        let next = (last + 8) & 0xfffffff8;
+        // Compute the index of the start of the next granularity-aligned chunk,
+        // if 'Region's are contiguous
+        let next = target.align_up(last + 1);
         let base = curr.base as usize;
 
         if base <= next {
             // Start of 'Region' is contiguous with the previous 'Region'
             // once aligned and padded (if required)
-            let npad = base - last - 1;
+            let npad = base.checked_sub(last + 1).expect("overlapping 'Region's");
             let mut pads = vec![0; npad];
             prev.data.append(&mut pads);
             prev.data.append(&mut curr.data);
@@ -214,3 +296,137 @@ pub fn merge_regions(regions: &[Region]) -> Vec<Region> {
     }
     result
 }
+
+/**
+ * Clip the 'Region's to the (union of the) 'include' address ranges, if any
+ * are given, and then cut out all 'exclude' address ranges.
+ */
+pub fn filter_regions(
+    regions: &[Region],
+    include: &[AddressRange],
+    exclude: &[AddressRange],
+) -> Vec<Region> {
+    let mut result: Vec<Region> = if include.is_empty() {
+        regions.to_vec()
+    } else {
+        let include = merge_ranges(include);
+        regions
+            .iter()
+            .flat_map(|r| include.iter().filter_map(|x| r.clip(x)))
+            .collect()
+    };
+    for x in exclude.iter() {
+        result = result.iter().flat_map(|r| r.exclude(x)).collect();
+    }
+    result.sort();
+    result
+}
+
+/**
+ * Move all 'Region's by a signed offset.
+ */
+pub fn offset_regions(regions: &[Region], offset: i64) -> Result<Vec<Region>, Error> {
+    regions.iter().map(|r| r.offset(offset)).collect()
+}
+
+/**
+ * Offset that moves the lowest address of the 'Region's to 'address'.
+ */
+pub fn relocation_offset(regions: &[Region], address: u32) -> i64 {
+    match regions.iter().map(|r| r.base).min() {
+        Some(lowest) => address as i64 - lowest as i64,
+        None => 0,
+    }
+}
+
+/**
+ * Sort, and then combine overlapping (or adjacent) address ranges.
+ */
+fn merge_ranges(ranges: &[AddressRange]) -> Vec<AddressRange> {
+    let mut sorted = ranges.to_vec();
+    sorted.sort();
+    let mut result: Vec<AddressRange> = Vec::new();
+    for r in sorted.into_iter() {
+        match result.last_mut() {
+            Some(last) if r.start() <= last.end() => {
+                *last = AddressRange::new(last.start(), last.end().max(r.end()));
+            }
+            _ => result.push(r),
+        }
+    }
+    result
+}
+
+//----------------------------------------------------------------------------
+// Tests
+//----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::{filter_regions, offset_regions, relocation_offset, Region};
+    use crate::error::Error;
+    use crate::range::AddressRange;
+
+    fn make_region(base: u32, size: usize) -> Region {
+        Region {
+            base,
+            data: (0..size).map(|x| x as u8).collect(),
+        }
+    }
+
+    #[test]
+    fn exclude_splits_regions() {
+        let regions = vec![make_region(0x1000, 0x100)];
+        let exclude = [AddressRange::new(0x1040, 0x1080)];
+        let result = filter_regions(&regions, &[], &exclude);
+        assert_eq!(result.len(), 2);
+        assert_eq!(result[0].range(), AddressRange::new(0x1000, 0x1040));
+        assert_eq!(result[1].range(), AddressRange::new(0x1080, 0x1100));
+        assert_eq!(result[1].data[0], 0x80);
+    }
+
+    #[test]
+    fn include_clips_regions() {
+        let regions = vec![make_region(0x1000, 0x100), make_region(0x2000, 0x100)];
+        let include = [
+            AddressRange::new(0x10f0, 0x2010),
+            AddressRange::new(0x2008, 0x2020),
+        ];
+        let result = filter_regions(&regions, &include, &[]);
+        assert_eq!(result.len(), 2);
+        assert_eq!(result[0].range(), AddressRange::new(0x10f0, 0x1100));
+        assert_eq!(result[1].range(), AddressRange::new(0x2000, 0x2020));
+        assert_eq!(result[0].data[0], 0xf0);
+    }
+
+    #[test]
+    fn exclude_everything() {
+        let regions = vec![make_region(0x1000, 0x100)];
+        let exclude = [AddressRange::new(0x0800, 0x1800)];
+        assert!(filter_regions(&regions, &[], &exclude).is_empty());
+    }
+
+    #[test]
+    fn relocate_to_bank_b() {
+        let regions = vec![
+            make_region(0x0800_c800, 0x100),
+            make_region(0x0801_0000, 0x10),
+        ];
+        let offset = relocation_offset(&regions, 0x0804_c800);
+        assert_eq!(offset, 0x4_0000);
+        let moved = offset_regions(&regions, offset).unwrap();
+        assert_eq!(moved[1].address(), 0x0805_0000);
+        assert_eq!(offset_regions(&moved, -offset).unwrap(), regions);
+    }
+
+    #[test]
+    fn relocation_overflow() {
+        let regions = vec![make_region(0xffff_fe00, 0x100)];
+        assert!(offset_regions(&regions, 0xff).is_ok());
+        assert!(matches!(
+            offset_regions(&regions, 0x100),
+            Err(Error::AddressOverflow { .. })
+        ));
+        assert!(offset_regions(&regions, -0xffff_fe01).is_err());
+    }
+}
@@ -0,0 +1,401 @@
+use clap::ValueEnum;
+use ed25519_dalek::{Signer as _, Verifier as _};
+use p256::ecdsa::signature::hazmat::{PrehashSigner, PrehashVerifier};
+use p256::pkcs8::{
+    DecodePrivateKey, DecodePublicKey, EncodePrivateKey, EncodePublicKey, LineEnding,
+};
+use rand_core::OsRng;
+use sha2::{Digest, Sha256};
+use std::io::Write;
+use std::path::Path;
+
+use crate::error::Error;
+use crate::patch::{apply_patches, Patch};
+use crate::region::Region;
+use crate::target::Target;
+use crate::update::FirmwareUpdate;
+
+/// Size of both Ed25519, and (fixed-size, 'r || s') ECDSA-P256, signatures.
+pub const SIGNATURE_SIZE: usize = 64;
+
+/**
+ * Signature algorithms, for signing firmware images.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum SignAlg {
+    #[default]
+    Ed25519,
+    EcdsaP256,
+}
+
+impl SignAlg {
+    pub fn name(&self) -> &'static str {
+        match self {
+            SignAlg::Ed25519 => "Ed25519",
+            SignAlg::EcdsaP256 => "ECDSA-P256",
+        }
+    }
+}
+
+/**
+ * Private key, for signing.
+ */
+#[derive(Debug, Clone)]
+pub enum SigningKey {
+    Ed25519(ed25519_dalek::SigningKey),
+    EcdsaP256(p256::ecdsa::SigningKey),
+}
+
+/**
+ * Public key, for verifying signatures.
+ */
+#[derive(Debug, Clone)]
+pub enum VerifyingKey {
+    Ed25519(ed25519_dalek::VerifyingKey),
+    EcdsaP256(p256::ecdsa::VerifyingKey),
+}
+
+fn read_key(path: &Path) -> Result<(Vec<u8>, Option<String>), Error> {
+    let bytes = std::fs::read(path)
+        .map_err(|e| Error::Signature(format!("cannot read '{}' ({})", path.display(), e)))?;
+    let pem = match std::str::from_utf8(&bytes) {
+        Ok(text) if text.trim_start().starts_with("-----BEGIN") => Some(text.to_string()),
+        _ => None,
+    };
+    Ok((bytes, pem))
+}
+
+fn key_error(path: &Path, alg: SignAlg) -> impl Fn(String) -> Error + '_ {
+    move |e| {
+        Error::Signature(format!(
+            "'{}' is not a valid {} key ({})",
+            path.display(),
+            alg.name(),
+            e
+        ))
+    }
+}
+
+impl SigningKey {
+    /**
+     * Read a private key, as either PEM (PKCS#8), or as the raw 32-byte
+     * secret.
+     */
+    pub fn from_file(path: &Path, alg: SignAlg) -> Result<Self, Error> {
+        let (bytes, pem) = read_key(path)?;
+        let err = key_error(path, alg);
+        match (alg, pem) {
+            (SignAlg::Ed25519, Some(pem)) => ed25519_dalek::SigningKey::from_pkcs8_pem(&pem)
+                .map(SigningKey::Ed25519)
+                .map_err(|e| err(e.to_string())),
+            (SignAlg::Ed25519, None) => {
+                let secret: [u8; 32] = bytes
+                    .try_into()
+                    .map_err(|_| err("expected 32 bytes".to_string()))?;
+                Ok(SigningKey::Ed25519(ed25519_dalek::SigningKey::from_bytes(
+                    &secret,
+                )))
+            }
+            (SignAlg::EcdsaP256, Some(pem)) => p256::ecdsa::SigningKey::from_pkcs8_pem(&pem)
+                .map(SigningKey::EcdsaP256)
+                .map_err(|e| err(e.to_string())),
+            (SignAlg::EcdsaP256, None) => p256::ecdsa::SigningKey::from_slice(&bytes)
+                .map(SigningKey::EcdsaP256)
+                .map_err(|e| err(e.to_string())),
+        }
+    }
+
+    /**
+     * Generate a (random) key, for testing.
+     */
+    pub fn generate(alg: SignAlg) -> Self {
+        match alg {
+            SignAlg::Ed25519 => {
+                SigningKey::Ed25519(ed25519_dalek::SigningKey::generate(&mut OsRng))
+            }
+            SignAlg::EcdsaP256 => {
+                SigningKey::EcdsaP256(p256::ecdsa::SigningKey::random(&mut OsRng))
+            }
+        }
+    }
+
+    pub fn alg(&self) -> SignAlg {
+        match self {
+            SigningKey::Ed25519(_) => SignAlg::Ed25519,
+            SigningKey::EcdsaP256(_) => SignAlg::EcdsaP256,
+        }
+    }
+
+    pub fn verifying_key(&self) -> VerifyingKey {
+        match self {
+            SigningKey::Ed25519(key) => VerifyingKey::Ed25519(key.verifying_key()),
+            SigningKey::EcdsaP256(key) => VerifyingKey::EcdsaP256(*key.verifying_key()),
+        }
+    }
+
+    pub fn to_pem(&self) -> String {
+        let pem = match self {
+            SigningKey::Ed25519(key) => key.to_pkcs8_pem(LineEnding::LF),
+            SigningKey::EcdsaP256(key) => key.to_pkcs8_pem(LineEnding::LF),
+        };
+        pem.expect("PKCS#8 encoding failed").to_string()
+    }
+
+    /**
+     * Sign the message, which is a SHA-256 digest if 'prehashed' is set. ECDSA
+     * then signs the digest as its hash (rather than hashing it again), so
+     * that the signature is the usual ECDSA-SHA256 one of the image; Ed25519
+     * signs the digest bytes as its message.
+     */
+    pub fn sign(&self, message: &[u8], prehashed: bool) -> Vec<u8> {
+        match self {
+            SigningKey::Ed25519(key) => key.sign(message).to_bytes().to_vec(),
+            SigningKey::EcdsaP256(key) => {
+                let signature: p256::ecdsa::Signature = match prehashed {
+                    true => key.sign_prehash(message).expect("invalid SHA-256 digest"),
+                    false => key.sign(message),
+                };
+                signature.to_bytes().to_vec()
+            }
+        }
+    }
+}
+
+impl VerifyingKey {
+    /**
+     * Read a public key, as either PEM (SPKI), or as raw bytes (32 bytes for
+     * Ed25519, or a SEC1-encoded point for ECDSA-P256).
+     */
+    pub fn from_file(path: &Path, alg: SignAlg) -> Result<Self, Error> {
+        let (bytes, pem) = read_key(path)?;
+        let err = key_error(path, alg);
+        match (alg, pem) {
+            (SignAlg::Ed25519, Some(pem)) => ed25519_dalek::VerifyingKey::from_public_key_pem(&pem)
+                .map(VerifyingKey::Ed25519)
+                .map_err(|e| err(e.to_string())),
+            (SignAlg::Ed25519, None) => {
+                let public: [u8; 32] = bytes
+                    .try_into()
+                    .map_err(|_| err("expected 32 bytes".to_string()))?;
+                ed25519_dalek::VerifyingKey::from_bytes(&public)
+                    .map(VerifyingKey::Ed25519)
+                    .map_err(|e| err(e.to_string()))
+            }
+            (SignAlg::EcdsaP256, Some(pem)) => p256::ecdsa::VerifyingKey::from_public_key_pem(&pem)
+                .map(VerifyingKey::EcdsaP256)
+                .map_err(|e| err(e.to_string())),
+            (SignAlg::EcdsaP256, None) => p256::ecdsa::VerifyingKey::from_sec1_bytes(&bytes)
+                .map(VerifyingKey::EcdsaP256)
+                .map_err(|e| err(e.to_string())),
+        }
+    }
+
+    pub fn to_pem(&self) -> String {
+        let pem = match self {
+            VerifyingKey::Ed25519(key) => key.to_public_key_pem(LineEnding::LF),
+            VerifyingKey::EcdsaP256(key) => key.to_public_key_pem(LineEnding::LF),
+        };
+        pem.expect("SPKI encoding failed")
+    }
+
+    /**
+     * Verify the signature of the message, which is a SHA-256 digest if
+     * 'prehashed' is set (as for 'SigningKey::sign').
+     */
+    pub fn verify(&self, message: &[u8], signature: &[u8], prehashed: bool) -> Result<(), Error> {
+        let err = |e: ed25519_dalek::SignatureError| {
+            Error::Signature(format!("verification failed ({})", e))
+        };
+        match self {
+            VerifyingKey::Ed25519(key) => {
+                let signature = ed25519_dalek::Signature::from_slice(signature).map_err(err)?;
+                key.verify(message, &signature).map_err(err)
+            }
+            VerifyingKey::EcdsaP256(key) => {
+                let signature = p256::ecdsa::Signature::from_slice(signature).map_err(err)?;
+                match prehashed {
+                    true => key.verify_prehash(message, &signature).map_err(err),
+                    false => key.verify(message, &signature).map_err(err),
+                }
+            }
+        }
+    }
+}
+
+/**
+ * Write a new key file, refusing to overwrite an existing one. A private key
+ * is only readable by its owner (on Unix).
+ */
+pub fn write_key_file(path: &Path, pem: &str, private: bool) -> Result<(), Error> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    if private {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    #[cfg(not(unix))]
+    let _ = private;
+    options
+        .open(path)
+        .and_then(|mut file| file.write_all(pem.as_bytes()))
+        .map_err(|e| Error::Signature(format!("cannot write '{}' ({})", path.display(), e)))
+}
+
+/**
+ * Bytes that are signed: either the image data itself, or its SHA-256
+ * digest, if 'digest' is set.
+ */
+pub fn signed_message(data: &[u8], digest: bool) -> Vec<u8> {
+    match digest {
+        true => Sha256::digest(data).to_vec(),
+        false => data.to_vec(),
+    }
+}
+
+/**
+ * Signing key, and where the signature goes. Without an 'address', the
+ * signature is appended to the binary output (and/or written to a file).
+ */
+#[derive(Debug, Clone)]
+pub struct Signer {
+    pub key: SigningKey,
+    pub digest: bool,
+    pub address: Option<u32>,
+}
+
+impl Signer {
+    pub fn sign(&self, data: &[u8]) -> Vec<u8> {
+        self.key
+            .sign(&signed_message(data, self.digest), self.digest)
+    }
+
+    /**
+     * Embed the signature at 'address', which must be aligned to the program
+     * granularity, and must follow all image data. The signature covers every
+     * byte of the firmware update that precedes it, including any padding.
+     */
+    pub fn embed(
+        &self,
+        regions: &mut Vec<Region>,
+        target: &Target,
+        append_crc: bool,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        let address = match self.address {
+            Some(address) => address,
+            None => return Ok(None),
+        };
+        if !target.is_aligned(address as usize) {
+            return Err(Error::Signature(format!(
+                "address 0x{:08x} is not aligned to {} bytes",
+                address,
+                target.granularity()
+            )));
+        }
+        if regions.iter().any(|r| r.range().end() > address) {
+            return Err(Error::Signature(format!(
+                "address 0x{:08x} must follow the image data",
+                address
+            )));
+        }
+        // Reserve the signature's space, so that the signed bytes (and padding)
+        // are exactly those that precede the signature, in the update
+        let blank = Patch::new(address, vec![0; SIGNATURE_SIZE]);
+        apply_patches(regions, &[blank], true)?;
+        let update = crate::make_update(regions, target, append_crc);
+        let (image, _) = split_signature(&update, address)?;
+        let signature = self.sign(&image);
+        apply_patches(regions, &[Patch::new(address, signature.clone())], false)?;
+        Ok(Some(signature))
+    }
+}
+
+/**
+ * Split the (contiguous) data of the firmware update into the image bytes
+ * that precede the signature at 'address', and the signature itself.
+ */
+pub fn split_signature(update: &FirmwareUpdate, address: u32) -> Result<(Vec<u8>, Vec<u8>), Error> {
+    let data = update.to_vec();
+    let base = update.packets().first().map(|p| p.address()).unwrap_or(0);
+    let start = address.wrapping_sub(base) as usize;
+    if address < base || start + SIGNATURE_SIZE > data.len() {
+        return Err(Error::Signature(format!(
+            "no signature at 0x{:08x} (size = {})",
+            address, SIGNATURE_SIZE
+        )));
+    }
+    let signature = data[start..start + SIGNATURE_SIZE].to_vec();
+    let mut image = data;
+    image.truncate(start);
+    Ok((image, signature))
+}
+
+//----------------------------------------------------------------------------
+// Tests
+//----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::{signed_message, split_signature, SignAlg, Signer, SigningKey};
+    use crate::region::Region;
+
+    #[test]
+    fn sign_and_verify() {
+        for alg in [SignAlg::Ed25519, SignAlg::EcdsaP256] {
+            let key = SigningKey::generate(alg);
+            let signature = key.sign(b"firmware", false);
+            assert_eq!(signature.len(), super::SIGNATURE_SIZE);
+            let public = key.verifying_key();
+            assert!(public.verify(b"firmware", &signature, false).is_ok());
+            assert!(public.verify(b"Firmware", &signature, false).is_err());
+        }
+    }
+
+    #[test]
+    fn ecdsa_signs_digest_once() {
+        // A standard ECDSA-SHA256 verifier, given the image, must accept the
+        // signature of its digest
+        let signer = Signer {
+            key: SigningKey::generate(SignAlg::EcdsaP256),
+            digest: true,
+            address: None,
+        };
+        let signature = signer.sign(b"firmware");
+        let public = signer.key.verifying_key();
+        assert!(public.verify(b"firmware", &signature, false).is_ok());
+        let message = signed_message(b"firmware", true);
+        assert!(public.verify(&message, &signature, true).is_ok());
+    }
+
+    #[test]
+    fn embedded_signature_round_trip() {
+        let data: Vec<u8> = (0..100).map(|x| x as u8).collect();
+        let mut regions = vec![Region::with_data(0x0800_0000, data)];
+        let signer = Signer {
+            key: SigningKey::generate(SignAlg::Ed25519),
+            digest: true,
+            address: Some(0x0800_0100),
+        };
+        let target = Default::default();
+        let signature = signer.embed(&mut regions, &target, false).unwrap();
+
+        // Signed bytes include the padding up to the signature
+        let update = crate::make_update(&regions, &target, false);
+        let (image, embedded) = split_signature(&update, 0x0800_0100).unwrap();
+        assert_eq!(image.len(), 0x100);
+        assert_eq!(Some(embedded.clone()), signature);
+        let message = signed_message(&image, true);
+        assert!(signer
+            .key
+            .verifying_key()
+            .verify(&message, &embedded, true)
+            .is_ok());
+
+        // The signature must follow all of the image data
+        let signer = Signer {
+            address: Some(0x0800_0020),
+            ..signer
+        };
+        assert!(signer.embed(&mut regions, &target, false).is_err());
+    }
+}
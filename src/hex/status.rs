@@ -0,0 +1,28 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Set when stdout carries data (e.g. the JSON summary), so that status
+/// messages must go to stderr instead.
+static TO_STDERR: AtomicBool = AtomicBool::new(false);
+
+pub fn set_to_stderr(on: bool) {
+    TO_STDERR.store(on, Ordering::Relaxed);
+}
+
+pub fn to_stderr() -> bool {
+    TO_STDERR.load(Ordering::Relaxed)
+}
+
+/**
+ * Print a (human-readable) status message, to stdout, or to stderr if stdout
+ * carries data.
+ */
+#[macro_export]
+macro_rules! status {
+    ($($arg:tt)*) => {
+        if $crate::hex::status::to_stderr() {
+            eprintln!($($arg)*)
+        } else {
+            println!($($arg)*)
+        }
+    };
+}
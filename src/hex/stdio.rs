@@ -0,0 +1,41 @@
+use std::io::{Read, Write};
+use std::path::Path;
+
+/// File name that stands for stdin (as an input), or stdout (as an output).
+pub const STDIO: &str = "-";
+
+pub fn is_stdio(path: &str) -> bool {
+    path == STDIO
+}
+
+/**
+ * Read an input file, or all of stdin, if the path is '-'.
+ */
+pub fn read_input(path: &Path) -> std::io::Result<Vec<u8>> {
+    if path == Path::new(STDIO) {
+        let mut bytes = Vec::new();
+        std::io::stdin().lock().read_to_end(&mut bytes)?;
+        return Ok(bytes);
+    }
+    std::fs::read(path)
+}
+
+/**
+ * Read a text input file, or all of stdin, if the path is '-'.
+ */
+pub fn read_input_text(path: &Path) -> std::io::Result<String> {
+    let bytes = read_input(path)?;
+    String::from_utf8(bytes).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+/**
+ * Write an output file, or write to stdout, if the file name is '-'.
+ */
+pub fn write_output(filename: &str, bytes: impl AsRef<[u8]>) -> std::io::Result<()> {
+    if is_stdio(filename) {
+        let mut stdout = std::io::stdout().lock();
+        stdout.write_all(bytes.as_ref())?;
+        return stdout.flush();
+    }
+    std::fs::write(filename, bytes)
+}
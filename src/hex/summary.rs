@@ -0,0 +1,201 @@
+use clap::ValueEnum;
+use serde::Serialize;
+
+use crate::packet::FirmwareUpdatePacket;
+use crate::region::Region;
+use crate::target::Target;
+use crate::update::FirmwareUpdate;
+
+/// Version of the JSON summary schema, which is bumped whenever a field is
+/// renamed, removed, or changes meaning (new fields may be added).
+pub const SCHEMA_VERSION: u32 = 1;
+
+/**
+ * Formats for the summary (on stdout).
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum SummaryFormat {
+    /// Human-readable
+    #[default]
+    Text,
+    /// JSON, with status messages going to stderr
+    Json,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RegionInfo {
+    pub address: u32,
+    pub length: usize,
+}
+
+impl From<&Region> for RegionInfo {
+    fn from(r: &Region) -> Self {
+        Self {
+            address: r.address(),
+            length: r.len(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PacketInfo {
+    pub kind: String,
+    pub address: u32,
+    pub length: usize,
+    pub crc16: u16,
+}
+
+impl From<&FirmwareUpdatePacket> for PacketInfo {
+    fn from(p: &FirmwareUpdatePacket) -> Self {
+        Self {
+            kind: p.kind().to_string(),
+            address: p.address(),
+            length: p.len(),
+            crc16: p.crc16(),
+        }
+    }
+}
+
+/**
+ * Fill inserted between the (merged) regions, and the padding that aligns
+ * the end of the image to the program granularity.
+ */
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FillInfo {
+    pub value: u8,
+    pub granularity: usize,
+    pub gap_bytes: usize,
+    pub padding_bytes: usize,
+    pub append_crc: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct OutputInfo {
+    pub format: String,
+    pub path: String,
+}
+
+/**
+ * Machine-readable summary of a conversion, with all addresses, lengths, and
+ * CRCs as numbers.
+ */
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Summary {
+    pub schema: u32,
+    pub input: String,
+    pub regions: Vec<RegionInfo>,
+    pub merged: Vec<RegionInfo>,
+    pub packets: Vec<PacketInfo>,
+    /// Packets as written to the packet outputs (e.g. with erase packets, or
+    /// compressed), which may differ from those of the firmware update
+    pub stream: Vec<PacketInfo>,
+    pub length: usize,
+    pub crc32: u32,
+    pub fill: FillInfo,
+    pub outputs: Vec<OutputInfo>,
+}
+
+impl Summary {
+    /**
+     * Summarise the firmware update, built from the 'regions' (before they
+     * were merged into 'merged').
+     */
+    pub fn new(
+        input: &str,
+        regions: &[Region],
+        merged: &[Region],
+        update: &FirmwareUpdate,
+        target: &Target,
+        append_crc: bool,
+    ) -> Self {
+        let data = regions.iter().fold(0, |s, r| s + r.len());
+        let span = match (merged.first(), merged.last()) {
+            (Some(first), Some(last)) => (last.range().end() - first.address()) as usize,
+            _ => 0,
+        };
+        Self {
+            schema: SCHEMA_VERSION,
+            input: input.to_string(),
+            regions: regions.iter().map(RegionInfo::from).collect(),
+            merged: merged.iter().map(RegionInfo::from).collect(),
+            packets: update.packets().iter().map(PacketInfo::from).collect(),
+            stream: Vec::new(),
+            length: update.len(),
+            crc32: update.crc32(),
+            fill: FillInfo {
+                value: 0,
+                granularity: target.granularity(),
+                gap_bytes: span - data,
+                padding_bytes: update.len().saturating_sub(span),
+                append_crc,
+            },
+            outputs: Vec::new(),
+        }
+    }
+
+    pub fn add_output(&mut self, format: &str, path: &str) {
+        self.outputs.push(OutputInfo {
+            format: format.to_string(),
+            path: path.to_string(),
+        });
+    }
+
+    pub fn set_stream(&mut self, packets: &[FirmwareUpdatePacket]) {
+        self.stream = packets.iter().map(PacketInfo::from).collect();
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+}
+
+//----------------------------------------------------------------------------
+// Tests
+//----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::Summary;
+    use crate::packet::FirmwareUpdatePacket;
+    use crate::region::{merge_regions, Region};
+
+    #[test]
+    fn summary_fill() {
+        let regions = vec![
+            Region::with_data(0x0800_0000, vec![1; 10]),
+            Region::with_data(0x0800_0010, vec![2; 5]),
+        ];
+        let target = Default::default();
+        let merged = merge_regions(&regions, &target);
+        let update = crate::make_update(&regions, &target, false);
+        let summary = Summary::new("-", &regions, &merged, &update, &target, false);
+
+        assert_eq!(summary.merged.len(), 1);
+        assert_eq!(summary.fill.gap_bytes, 6);
+        assert_eq!(summary.fill.padding_bytes, update.len() - 0x15);
+        assert_eq!(summary.length, update.len());
+        let json: serde_json::Value = serde_json::from_str(&summary.to_json()).unwrap();
+        assert_eq!(json["schema"], 1);
+        assert_eq!(json["crc32"], update.crc32());
+        assert_eq!(json["packets"][0]["address"], 0x0800_0000);
+        assert_eq!(json["stream"], serde_json::json!([]));
+    }
+
+    #[test]
+    fn summary_stream() {
+        let regions = vec![Region::with_data(0x0800_0000, vec![1; 10])];
+        let target = Default::default();
+        let update = crate::make_update(&regions, &target, false);
+        let mut summary = Summary::new("-", &regions, &regions, &update, &target, false);
+        let mut stream = vec![FirmwareUpdatePacket::erase(0x0800_0000, 0x800)];
+        stream.extend_from_slice(update.packets());
+        summary.set_stream(&stream);
+
+        let json: serde_json::Value = serde_json::from_str(&summary.to_json()).unwrap();
+        assert_eq!(json["packets"].as_array().unwrap().len(), 1);
+        assert_eq!(json["stream"].as_array().unwrap().len(), 2);
+        assert_eq!(json["stream"][0]["kind"], "e");
+        assert_eq!(json["stream"][1]["kind"], "u");
+        assert_eq!(json["stream"][1]["crc16"], update.packets()[0].crc16());
+    }
+}
@@ -0,0 +1,115 @@
+use crate::packet::MAX_DATA_LENGTH;
+
+/**
+ * Flash-programming properties of the target MCU, so that padding and packet
+ * sizes match what the bootloader's flash-write routine expects.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Target {
+    granularity: usize,
+}
+
+impl Default for Target {
+    fn default() -> Self {
+        Self::STM32G4
+    }
+}
+
+impl Target {
+    /// Word-programming, when running at the typical 2.7-3.6 V supply range.
+    pub const STM32F4: Target = Target { granularity: 4 };
+    /// Double-word programming, via 'HAL_FLASH_Program(..)'.
+    pub const STM32G4: Target = Target { granularity: 8 };
+    /// Flash-word (256-bit) programming.
+    pub const STM32H7: Target = Target { granularity: 32 };
+
+    /**
+     * Program granularity must be a power-of-two, and no larger than a 32 B
+     * flash-word.
+     */
+    pub fn new(granularity: usize) -> Option<Self> {
+        if granularity.is_power_of_two() && granularity <= 32 {
+            Some(Self { granularity })
+        } else {
+            None
+        }
+    }
+
+    pub fn granularity(&self) -> usize {
+        self.granularity
+    }
+
+    /**
+     * Round 'size' up to the next multiple of the program granularity.
+     */
+    pub fn align_up(&self, size: usize) -> usize {
+        (size + self.granularity - 1) & !(self.granularity - 1)
+    }
+
+    pub fn is_aligned(&self, size: usize) -> bool {
+        size & (self.granularity - 1) == 0
+    }
+
+    /**
+     * Largest packet payload that keeps every packet (start-)address aligned
+     * to the program granularity.
+     */
+    pub fn packet_size(&self) -> usize {
+        MAX_DATA_LENGTH & !(self.granularity - 1)
+    }
+}
+
+/**
+ * Parse a program granularity, in bytes, from the command-line.
+ */
+pub fn parse_granularity(arg: &str) -> Result<Target, String> {
+    match arg {
+        "stm32f4" => return Ok(Target::STM32F4),
+        "stm32g4" => return Ok(Target::STM32G4),
+        "stm32h7" => return Ok(Target::STM32H7),
+        _ => (),
+    }
+    let size: usize = arg.parse().map_err(|e| format!("{}", e))?;
+    Target::new(size).ok_or(format!(
+        "granularity must be one of 1, 2, 4, 8, 16, or 32 bytes (found {})",
+        size
+    ))
+}
+
+//----------------------------------------------------------------------------
+// Tests
+//----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_granularity, Target};
+    use crate::packet::MAX_DATA_LENGTH;
+
+    #[test]
+    fn packet_sizes_are_aligned() {
+        for g in [1, 2, 4, 8, 16, 32] {
+            let target = Target::new(g).unwrap();
+            assert!(target.packet_size() <= MAX_DATA_LENGTH);
+            assert!(target.is_aligned(target.packet_size()));
+        }
+        assert_eq!(Target::STM32G4.packet_size(), MAX_DATA_LENGTH);
+        assert_eq!(Target::STM32H7.packet_size(), 192);
+    }
+
+    #[test]
+    fn align_up_to_granularity() {
+        assert_eq!(Target::STM32G4.align_up(0), 0);
+        assert_eq!(Target::STM32G4.align_up(3), 8);
+        assert_eq!(Target::STM32G4.align_up(8), 8);
+        assert_eq!(Target::STM32H7.align_up(33), 64);
+        assert_eq!(Target::new(1).unwrap().align_up(5), 5);
+    }
+
+    #[test]
+    fn reject_invalid_granularity() {
+        assert!(parse_granularity("12").is_err());
+        assert!(parse_granularity("64").is_err());
+        assert!(parse_granularity("0").is_err());
+        assert_eq!(parse_granularity("stm32h7"), Ok(Target::STM32H7));
+    }
+}
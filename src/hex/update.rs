@@ -1,5 +1,7 @@
+use crate::digest::DigestAlg;
 use crate::hexcrc::calc_stm32_crc;
 use crate::packet::FirmwareUpdatePacket;
+use crate::region::Region;
 
 /**
  * Complete firmware update.
@@ -9,6 +11,7 @@ pub struct FirmwareUpdate {
     packets: Vec<FirmwareUpdatePacket>,
     length: usize,
     crc32: u32,
+    encrypted: bool,
 }
 
 impl FirmwareUpdate {
@@ -19,6 +22,19 @@ impl FirmwareUpdate {
             packets,
             length,
             crc32,
+            encrypted: false,
+        }
+    }
+
+    /**
+     * Firmware update whose packets carry ciphertext, and with the CRC32 of
+     * the plaintext, for verification by the device (after decryption).
+     */
+    pub fn encrypted(packets: Vec<FirmwareUpdatePacket>, crc32: u32) -> Self {
+        Self {
+            crc32,
+            encrypted: true,
+            ..Self::new(packets)
         }
     }
     pub fn len(&self) -> usize {
@@ -27,7 +43,33 @@ impl FirmwareUpdate {
     pub fn crc32(&self) -> u32 {
         self.crc32
     }
+    pub fn is_encrypted(&self) -> bool {
+        self.encrypted
+    }
     pub fn packets(&self) -> &[FirmwareUpdatePacket] {
         &self.packets
     }
+
+    /**
+     * All of the firmware (binary-)data, in packet order.
+     */
+    pub fn to_vec(&self) -> Vec<u8> {
+        self.packets.iter().flat_map(|p| p.to_vec()).collect()
+    }
+
+    /**
+     * All of the firmware data, as a single 'Region', starting at the address
+     * of the first packet (the data of 'make_update' is contiguous).
+     */
+    pub fn to_region(&self) -> Option<Region> {
+        let base = self.packets.first()?.address();
+        Some(Region::with_data(base, self.to_vec()))
+    }
+
+    /**
+     * Cryptographic digest of all of the firmware (binary-)data.
+     */
+    pub fn digest(&self, alg: DigestAlg) -> Vec<u8> {
+        alg.digest(&self.to_vec())
+    }
 }
@@ -0,0 +1,151 @@
+use std::path::Path;
+
+use crate::error::Error;
+use crate::hexcrc::stm32_crc;
+use crate::input::read_hex_file;
+use crate::overlap::OverlapPolicy;
+use crate::region::Region;
+use crate::stdio::read_input;
+use crate::target::Target;
+
+/**
+ * Read an image to verify: a BIN file (e.g. a dump read back from a device),
+ * placed at 'base', if the file has a '.bin' extension, or if a 'base' is
+ * given; or else a HEX file, with any gaps zero-filled (as for the BIN
+ * output).
+ */
+pub fn read_image(path: &Path, base: Option<u32>, policy: OverlapPolicy) -> Result<Region, Error> {
+    let is_bin = path
+        .extension()
+        .is_some_and(|x| x.eq_ignore_ascii_case("bin"));
+    if is_bin || base.is_some() {
+        let bytes = read_input(path)
+            .map_err(|e| Error::Input(format!("cannot read '{}' ({})", path.display(), e)))?;
+        let base = base.unwrap_or(0);
+        if u32::try_from(bytes.len())
+            .ok()
+            .and_then(|n| base.checked_add(n))
+            .is_none()
+        {
+            return Err(Error::Verify(format!(
+                "'{}' ({} bytes) does not fit at 0x{:08x}",
+                path.display(),
+                bytes.len(),
+                base
+            )));
+        }
+        return Ok(Region::with_data(base, bytes));
+    }
+    let regions = read_hex_file(path, policy)?;
+    Region::single_region(&regions)
+        .ok_or_else(|| Error::Input(format!("'{}' holds no data", path.display())))
+}
+
+/**
+ * Split the image into its data, and the CRC32 that was appended to it. With
+ * a known 'length' (of the data), the CRC32 must follow it directly; else the
+ * CRC32 is searched for within the final (granularity-)padding, taking the
+ * last word that matches the CRC32 of the data that precedes it.
+ */
+pub fn split_appended_crc(
+    image: &Region,
+    length: Option<usize>,
+    target: &Target,
+) -> Result<(Region, u32), Error> {
+    let data = image.data();
+    let word = |n: usize| u32::from_le_bytes(data[n..n + 4].try_into().unwrap());
+    if let Some(n) = length {
+        if n + 4 > data.len() {
+            return Err(Error::Verify(format!(
+                "no CRC32 after {} bytes (image length = {})",
+                n,
+                data.len()
+            )));
+        }
+        return Ok((
+            Region::with_data(image.address(), data[..n].to_vec()),
+            word(n),
+        ));
+    }
+    let last = data
+        .len()
+        .checked_sub(4)
+        .ok_or_else(|| Error::Verify(format!("image is too short ({} bytes)", data.len())))?;
+    let first = last.saturating_sub(target.granularity() - 1);
+    (first..=last)
+        .rev()
+        .find(|n| stm32_crc(&data[..*n]) == word(*n))
+        .map(|n| {
+            (
+                Region::with_data(image.address(), data[..n].to_vec()),
+                word(n),
+            )
+        })
+        .ok_or_else(|| {
+            Error::Verify(
+                "no appended CRC32 matches the data (see '--length', for device dumps)".to_string(),
+            )
+        })
+}
+
+/**
+ * Offset of the first byte that differs between the two images, including
+ * where one image is longer than the other.
+ */
+pub fn first_difference(a: &[u8], b: &[u8]) -> Option<usize> {
+    match a.iter().zip(b.iter()).position(|(x, y)| x != y) {
+        Some(n) => Some(n),
+        None if a.len() != b.len() => Some(a.len().min(b.len())),
+        None => None,
+    }
+}
+
+//----------------------------------------------------------------------------
+// Tests
+//----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::{first_difference, read_image, split_appended_crc};
+    use crate::hexcrc::stm32_crc;
+    use crate::region::Region;
+
+    #[test]
+    fn find_appended_crc() {
+        // As written by 'to_binary_file': data, CRC32, and then zero-padding
+        let data: Vec<u8> = (0..101).map(|x| x as u8).collect();
+        let crc32 = stm32_crc(&data);
+        let mut bin = data.clone();
+        bin.extend(crc32.to_le_bytes());
+        bin.resize(112, 0);
+        let image = Region::with_data(0x0800_0000, bin);
+        let target = Default::default();
+
+        let (found, crc) = split_appended_crc(&image, None, &target).unwrap();
+        assert_eq!((found.data(), crc), (&data[..], crc32));
+        let (found, _) = split_appended_crc(&image, Some(101), &target).unwrap();
+        assert_eq!(found.data(), &data[..]);
+
+        // A corrupted byte means that no CRC32 matches
+        let mut bad = image.data().to_vec();
+        bad[50] ^= 1;
+        let bad = Region::with_data(0x0800_0000, bad);
+        assert!(split_appended_crc(&bad, None, &target).is_err());
+    }
+
+    #[test]
+    fn first_differing_offset() {
+        assert_eq!(first_difference(b"abcd", b"abcd"), None);
+        assert_eq!(first_difference(b"abcd", b"abXd"), Some(2));
+        assert_eq!(first_difference(b"abcd", b"abc"), Some(3));
+    }
+
+    #[test]
+    fn reject_bin_past_4gb() {
+        let path = std::env::temp_dir().join("ihex-crc32-verify-test.bin");
+        std::fs::write(&path, [0; 0x200]).unwrap();
+        let policy = Default::default();
+        assert!(read_image(&path, Some(0xffff_fd00), policy).is_ok());
+        assert!(read_image(&path, Some(0xffff_ff00), policy).is_err());
+    }
+}
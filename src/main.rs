@@ -1,11 +1,47 @@
+pub(crate) use hex::batch::BatchFormat;
 pub(crate) use hex::*;
 pub(crate) mod hex;
-use clap::Parser;
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+
+mod commands;
 
 // -- Data types for command-line options -- //
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Without a subcommand, the options of 'convert' apply
+    #[command(flatten)]
+    args: Args,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Print the regions, length, CRC32, and entry point (or a memory map, or hexdump) of a HEX file
+    Info(commands::InfoArgs),
+    /// Convert a HEX file into BIN, HEX, C include, and packet files (the default)
+    Convert(Box<Args>),
+    /// Check the CRC32 of a HEX file against an expected CRC32, or a reference file
+    Verify(commands::VerifyArgs),
+    /// Check a generated C include file against its declared CRC32, and a HEX file
+    CheckHeader(commands::CheckHeaderArgs),
+    /// Report the ranges, packets, and flash pages that differ between two HEX files
+    Diff(commands::DiffArgs),
+    /// Write bytes into a HEX file
+    Patch(commands::PatchArgs),
+    /// Send the firmware-update packets to a (serial) port
+    Send(commands::SendArgs),
+}
+
+#[derive(clap::Args, Debug)]
 struct Args {
+    /// Read settings from a TOML file (default: 'ihex-crc32.toml', if present), which options override
+    #[arg(long, value_name = "FILE.TOML")]
+    config: Option<String>,
+
     #[arg(short, long, value_name = "IN.HEX", default_value = "data/example.hex")]
     file: String,
 
@@ -15,9 +51,238 @@ struct Args {
     #[arg(short, long, value_name = "OUT.BIN")]
     binary: Option<String>,
 
-    #[arg(short, long)]
+    #[arg(short = 'x', long, value_name = "OUT.HEX")]
+    hex: Option<String>,
+
+    #[arg(short, long, value_name = "OUT.PKT")]
+    packets: Option<String>,
+
+    #[arg(short, long, overrides_with = "no_append_crc")]
     append_crc: bool,
 
+    /// Do not append the CRC32 (e.g. when the config file sets 'append_crc')
+    #[arg(long, overrides_with = "append_crc")]
+    no_append_crc: bool,
+
+    /// Write an output, with its own options: 'FORMAT:PATH[,crc|,nocrc][,prefix=NAME]',
+    /// where FORMAT is one of: include, binary, hex, packets (repeatable)
+    #[arg(short, long, value_name = "SPEC", value_parser = output::parse_output)]
+    output: Vec<OutputSpec>,
+
+    /// Digests of the firmware update, for the summary and the C header (default: none)
+    #[arg(long, value_enum, value_delimiter = ',')]
+    digest: Vec<DigestAlg>,
+
+    /// Prefix of the names of the C constants (e.g. 'kBootloaderFirmwareCrc')
+    #[arg(long, value_name = "NAME", default_value = append::DEFAULT_SYMBOL_PREFIX)]
+    symbol_prefix: String,
+
+    /// Append the digest(s) to the binary file (after the CRC32, if appended)
+    #[arg(long)]
+    append_digest: bool,
+
+    /// Flash program granularity, in bytes (or one of: stm32f4, stm32g4, stm32h7)
+    #[arg(short, long, value_name = "BYTES", value_parser = target::parse_granularity)]
+    granularity: Option<Target>,
+
+    /// Only keep data within the address range ('START..END' or 'START+SIZE')
+    #[arg(long, value_name = "RANGE", value_parser = range::parse_range)]
+    include_range: Vec<AddressRange>,
+
+    /// Remove data within the address range ('START..END' or 'START+SIZE')
+    #[arg(long, value_name = "RANGE", value_parser = range::parse_range)]
+    exclude_range: Vec<AddressRange>,
+
+    /// Move all data by a signed offset (e.g. '+0x40000', for bank B)
+    #[arg(long, value_name = "OFFSET", value_parser = range::parse_offset, allow_hyphen_values = true)]
+    offset: Option<i64>,
+
+    /// Move all data so that its lowest address is at ADDR
+    #[arg(long, value_name = "ADDR", value_parser = range::parse_address, conflicts_with = "offset")]
+    relocate: Option<u32>,
+
+    /// How to handle overlapping (or duplicate) data records
+    #[arg(long, value_enum, default_value_t = OverlapPolicy::Error)]
+    overlap: OverlapPolicy,
+
+    /// Write bytes into the image, e.g. '0x0801f800=DEADBEEF'
+    #[arg(long, value_name = "ADDR=HEX-BYTES", value_parser = patch::parse_bytes_patch)]
+    set: Vec<Patch>,
+
+    /// Write a 32-bit value into the image, e.g. '0x0801f800=1234'
+    #[arg(long, value_name = "ADDR=VALUE", value_parser = patch::parse_u32_patch)]
+    set_u32: Vec<(u32, u32)>,
+
+    /// Read patches from a file ('set ADDR=HEX-BYTES' or 'set-u32 ADDR=VALUE' lines)
+    #[arg(long, value_name = "FILE")]
+    patch_file: Option<String>,
+
+    /// Byte-order for 32-bit patch values
+    #[arg(long, value_enum, default_value_t = Endian::Little)]
+    endian: Endian,
+
+    /// Allow patches to add data where the image has none
+    #[arg(long, overrides_with = "no_extend")]
+    extend: bool,
+
+    /// Do not allow patches to add data (e.g. when the config file sets 'extend')
+    #[arg(long, overrides_with = "extend")]
+    no_extend: bool,
+
+    /// Fill in a metadata header, using the given TOML layout file
+    #[arg(long, value_name = "LAYOUT.TOML")]
+    metadata: Option<String>,
+
+    /// Address of the metadata header (overrides the layout file)
+    #[arg(long, value_name = "ADDR", value_parser = range::parse_address)]
+    metadata_address: Option<u32>,
+
+    /// Allow the metadata header to overwrite image data (e.g. a placeholder that the linker reserved)
+    #[arg(long, requires = "metadata")]
+    metadata_overwrite: bool,
+
+    /// Firmware version, for the metadata header ('MAJOR.MINOR.PATCH', or a number)
+    #[arg(long, value_name = "VERSION", value_parser = metadata::parse_version)]
+    fw_version: Option<u64>,
+
+    /// Git commit hash, for the metadata header
+    #[arg(long, value_name = "HASH", value_parser = patch::parse_hex_bytes)]
+    git_hash: Option<std::vec::Vec<u8>>,
+
+    /// Build time, for the metadata header (default: 'SOURCE_DATE_EPOCH', or now)
+    #[arg(long, value_name = "SECONDS")]
+    timestamp: Option<u64>,
+
+    /// Generate a key pair for testing ('PREFIX.pem' and 'PREFIX.pub.pem'), and exit
+    #[arg(long, value_name = "PREFIX")]
+    keygen: Option<String>,
+
+    /// Sign the image, using the private key (PEM/PKCS#8, or the raw 32 bytes)
+    #[arg(long, value_name = "KEY")]
+    sign: Option<String>,
+
+    /// Signature algorithm, for signing, verifying, and key generation
+    #[arg(long, value_enum, default_value_t = SignAlg::Ed25519)]
+    sign_alg: SignAlg,
+
+    /// Sign (or verify) the SHA-256 digest of the image, instead of the image itself
+    #[arg(long)]
+    sign_digest: bool,
+
+    /// Embed the signature at ADDR (after all image data), instead of appending it to the BIN
+    #[arg(long, value_name = "ADDR", value_parser = range::parse_address)]
+    signature_address: Option<u32>,
+
+    /// Write the signature to (or, when verifying, read it from) a file
+    #[arg(long, value_name = "FILE.SIG")]
+    signature_file: Option<String>,
+
+    /// Verify the signature of the image, using the public key (PEM/SPKI, or raw), and exit
+    #[arg(long, value_name = "PUBKEY", conflicts_with = "sign")]
+    verify_signature: Option<String>,
+
+    /// Encrypt the firmware payload, using the AES-128 (or AES-256) key (raw, or hex-digits)
+    #[arg(long, value_name = "KEY", conflicts_with_all = ["batch_serials", "batch_csv"])]
+    encrypt: Option<String>,
+
+    /// Block-cipher mode, for encryption
+    #[arg(long, value_enum, default_value_t = CipherMode::Ctr)]
+    cipher_mode: CipherMode,
+
+    /// Initialisation vector, for encryption (default: random)
+    #[arg(long, value_name = "HEX", value_parser = encrypt::parse_iv)]
+    iv: Option<[u8; 16]>,
+
+    /// Build a delta update, of just the flash pages that differ from the old (installed) image
+    #[arg(long, value_name = "OLD.HEX", conflicts_with = "encrypt")]
+    diff_from: Option<String>,
+
+    /// Write the packets of the delta update
+    #[arg(long, value_name = "OUT.PKT", requires = "diff_from")]
+    delta: Option<String>,
+
+    /// Write the changed pages, and the length and CRC32 of the new image, as JSON
+    #[arg(long, value_name = "OUT.JSON", requires = "diff_from")]
+    delta_list: Option<String>,
+
+    /// Flash page size, for delta updates (default: from the device profile, or 2048)
+    #[arg(long, value_name = "BYTES", value_parser = delta::parse_page_size)]
+    page_size: Option<u32>,
+
+    /// Only write packets for the pages that differ from the installed image
+    #[arg(long, value_name = "OLD.HEX", conflicts_with = "encrypt")]
+    installed: Option<String>,
+
+    /// Only write packets whose CRC16 differs from the device-reported list ('ADDR CRC16' lines)
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["encrypt", "installed"])]
+    installed_crc16: Option<String>,
+
+    /// Compress the image, before packetising (with a header packet, for the bootloader)
+    #[arg(long, value_enum, value_name = "FORMAT", conflicts_with_all = ["encrypt", "installed", "installed_crc16"])]
+    compress: Option<Compression>,
+
+    /// Write an MCUboot image (header, image, and SHA-256 TLV) as a binary file
+    #[arg(long, value_name = "OUT.BIN")]
+    mcuboot: Option<String>,
+
+    /// Version for the MCUboot header ('MAJOR.MINOR.REVISION+BUILD')
+    #[arg(long, value_name = "VERSION", value_parser = mcuboot::parse_image_version, default_value = "0.0.0")]
+    mcuboot_version: mcuboot::ImageVersion,
+
+    /// Size of the MCUboot header, which is placed just before the image
+    #[arg(long, value_name = "BYTES", value_parser = range::parse_address, default_value = "0x200")]
+    mcuboot_header_size: u32,
+
+    /// Load address, for RAM-loaded MCUboot images
+    #[arg(long, value_name = "ADDR", value_parser = range::parse_address)]
+    mcuboot_load_addr: Option<u32>,
+
+    /// Write one image per serial number ('FIRST..END' or 'FIRST+COUNT')
+    #[arg(long, value_name = "RANGE", value_parser = batch::parse_serials, requires = "batch_address")]
+    batch_serials: Option<(u32, u32)>,
+
+    /// Write one image per unit, from a CSV file of serial numbers (and other values)
+    #[arg(
+        long,
+        value_name = "FILE.CSV",
+        requires = "batch_address",
+        conflicts_with = "batch_serials"
+    )]
+    batch_csv: Option<String>,
+
+    /// Address of the (32-bit) serial number, within each unit's image
+    #[arg(long, value_name = "ADDR", value_parser = range::parse_address)]
+    batch_address: Option<u32>,
+
+    /// Output directory for the images (and manifest) of a batch
+    #[arg(long, value_name = "DIR", default_value = ".")]
+    batch_dir: String,
+
+    /// File name for each unit's image(s), with '{serial}' replaced by the serial number
+    #[arg(long, value_name = "PATTERN", default_value = "unit-{serial}")]
+    batch_name: String,
+
+    /// Output formats for each unit's image
+    #[arg(long, value_enum, value_delimiter = ',', default_value = "hex")]
+    batch_format: Vec<BatchFormat>,
+
+    /// Device profile, for validating addresses (built-in name, or TOML/JSON file)
+    #[arg(short, long, value_name = "NAME|FILE")]
+    device: Option<String>,
+
+    /// Write the flash sectors to erase, as JSON (requires a device profile)
+    #[arg(long, value_name = "OUT.JSON")]
+    erase_list: Option<String>,
+
+    /// Precede the data packets with erase-command packets (requires a device profile, and a packets output)
+    #[arg(long)]
+    erase_packets: bool,
+
+    /// Format of the summary: 'json' prints a machine-readable summary, and
+    /// moves the status messages to stderr
+    #[arg(long, value_enum, default_value_t = SummaryFormat::Text)]
+    format: SummaryFormat,
+
     /// Verbosity of generated output?
     #[arg(short, long, action = clap::ArgAction::Count)]
     verbose: u8,
@@ -40,98 +305,671 @@ pub fn test_stm32crc() {
     println!("STM32CRC: {:08x} (len = {})", val, STR.len());
 }
 
+fn exit_with_error(err: Error) -> ! {
+    eprintln!("ERROR: {}", err);
+    std::process::exit(1)
+}
+
 fn main() {
-    let args = Args::parse();
-    let path = args.file;
-    let data = std::fs::read_to_string(path).unwrap();
-    let reader = ihex::Reader::new_with_options(
-        &data,
-        ihex::ReaderOptions {
-            stop_after_first_error: true,
-            stop_after_eof: true,
-        },
-    );
-    let mut records: Vec<ihex::Record> = reader.into_iter().filter_map(|x| x.ok()).collect();
-    let regions = Region::build_regions(&mut records);
-    if !regions.is_empty() {
-        println!("\nFound {} HEX regions", regions.len());
-    }
-    if args.verbose > 0 {
-        for r in regions.iter() {
-            println!(" - Region: ADDR = {:08x}, SIZE = {}", r.address(), r.len());
+    let matches = Cli::command().get_matches();
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    match cli.command {
+        Some(Command::Info(args)) => commands::info(args),
+        Some(Command::Convert(args)) => {
+            let matches = matches.subcommand_matches("convert").unwrap();
+            convert(configure(*args, matches))
         }
+        Some(Command::Verify(args)) => commands::verify(args),
+        Some(Command::CheckHeader(args)) => commands::check_header(args),
+        Some(Command::Diff(args)) => commands::diff(args),
+        Some(Command::Patch(args)) => commands::patch(args),
+        Some(Command::Send(args)) => commands::send(args),
+        None => convert(configure(cli.args, &matches)),
     }
+}
 
-    let mut regions = merge_regions(&regions);
-    if args.verbose > 0 {
-        if !regions.is_empty() {
-            println!("\nFound {} HEX regions", regions.len());
+/**
+ * Fill in the options that were not given on the command-line from the
+ * config file (if any).
+ */
+fn configure(args: Args, matches: &ArgMatches) -> Args {
+    let path = match &args.config {
+        Some(path) => std::path::PathBuf::from(path),
+        None => match std::path::Path::new(config::CONFIG_FILE) {
+            path if path.is_file() => path.to_path_buf(),
+            _ => return args,
+        },
+    };
+    let config = Config::from_file(&path).unwrap_or_else(|e| exit_with_error(e));
+    apply_config(args, config, matches).unwrap_or_else(|e| exit_with_error(e))
+}
+
+fn parse_value<T>(key: &str, x: &str, f: impl Fn(&str) -> Result<T, String>) -> Result<T, Error> {
+    f(x).map_err(|e| Error::Config(format!("'{}': {}", key, e)))
+}
+
+fn parse_values<T>(
+    key: &str,
+    xs: &[String],
+    f: impl Fn(&str) -> Result<T, String>,
+) -> Result<Vec<T>, Error> {
+    xs.iter().map(|x| parse_value(key, x, &f)).collect()
+}
+
+fn parse_enum<T: ValueEnum>(x: &str) -> Result<T, String> {
+    T::from_str(x, true)
+}
+
+fn apply_config(mut args: Args, config: Config, matches: &ArgMatches) -> Result<Args, Error> {
+    let given = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+    if let (Some(x), false) = (config.input, given("file")) {
+        args.file = x;
+    }
+    if let (Some(x), false) = (config.granularity, given("granularity")) {
+        args.granularity = Some(parse_value("granularity", &x, target::parse_granularity)?);
+    }
+    if let (Some(x), false) = (config.device, given("device")) {
+        args.device = Some(x);
+    }
+    // '--no-append-crc' (and '--no-extend') override the config file, too
+    if let (Some(x), false) = (
+        config.append_crc,
+        given("append_crc") || given("no_append_crc"),
+    ) {
+        args.append_crc = x;
+    }
+    if let (Some(x), false) = (config.overlap, given("overlap")) {
+        args.overlap = parse_value("overlap", &x, parse_enum)?;
+    }
+    if let (Some(xs), false) = (config.include_range, given("include_range")) {
+        args.include_range = parse_values("include_range", &xs, range::parse_range)?;
+    }
+    if let (Some(xs), false) = (config.exclude_range, given("exclude_range")) {
+        args.exclude_range = parse_values("exclude_range", &xs, range::parse_range)?;
+    }
+    // Either option, on the command-line, overrides both in the config file
+    if !given("offset") && !given("relocate") {
+        if let Some(x) = config.offset {
+            args.offset = Some(parse_value("offset", &x, range::parse_offset)?);
         }
-        for r in regions.iter() {
-            println!(" - Region: ADDR = {:08x}, SIZE = {}", r.address(), r.len());
+        if let Some(x) = config.relocate {
+            args.relocate = Some(parse_value("relocate", &x, range::parse_address)?);
         }
     }
+    if let (Some(xs), false) = (config.set, given("set")) {
+        args.set = parse_values("set", &xs, patch::parse_bytes_patch)?;
+    }
+    if let (Some(xs), false) = (config.set_u32, given("set_u32")) {
+        args.set_u32 = parse_values("set_u32", &xs, patch::parse_u32_patch)?;
+    }
+    if let (Some(x), false) = (config.patch_file, given("patch_file")) {
+        args.patch_file = Some(x);
+    }
+    if let (Some(x), false) = (config.endian, given("endian")) {
+        args.endian = parse_value("endian", &x, parse_enum)?;
+    }
+    if let (Some(x), false) = (config.extend, given("extend") || given("no_extend")) {
+        args.extend = x;
+    }
+    if let (Some(xs), false) = (config.digest, given("digest")) {
+        args.digest = parse_values("digest", &xs, parse_enum)?;
+    }
+    if let (Some(x), false) = (config.symbol_prefix, given("symbol_prefix")) {
+        args.symbol_prefix = x;
+    }
+    if let (Some(xs), false) = (config.output, given("output")) {
+        args.output = parse_values("output", &xs, output::parse_output)?;
+    }
+    let outputs = config.outputs;
+    if let (Some(x), false) = (outputs.include, given("include")) {
+        args.include = Some(x);
+    }
+    if let (Some(x), false) = (outputs.binary, given("binary")) {
+        args.binary = Some(x);
+    }
+    if let (Some(x), false) = (outputs.hex, given("hex")) {
+        args.hex = Some(x);
+    }
+    if let (Some(x), false) = (outputs.packets, given("packets")) {
+        args.packets = Some(x);
+    }
+    Ok(args)
+}
 
-    let packets = if let Some(mut r) = Region::single_region(&regions) {
-        //
-        //  M O N O  !!
-        //
-        println!("\nBuild HEX mono-region");
-        println!(" - Region: ADDR = {:08x}, SIZE = {}", r.address(), r.len());
+/**
+ * Status messages go to stderr, if stdout carries data, and at most one of
+ * the outputs may go to stdout.
+ */
+fn route_status(outputs: &[&Option<String>], json: bool) {
+    let count = outputs
+        .iter()
+        .filter(|x| x.as_deref().is_some_and(stdio::is_stdio))
+        .count()
+        + json as usize;
+    if count > 1 {
+        exit_with_error(Error::Output(
+            "only one output can be written to stdout ('-')".to_string(),
+        ));
+    }
+    status::set_to_stderr(count > 0);
+}
 
-        let packets = r.to_packets(!args.append_crc);
-        if args.verbose > 0 && !packets.is_empty() {
-            println!("\nFound {} HEX packets", packets.len());
+fn convert(args: Args) {
+    let specs: Vec<Option<String>> = args.output.iter().map(|x| Some(x.path.clone())).collect();
+    let mut outputs = vec![
+        &args.include,
+        &args.binary,
+        &args.hex,
+        &args.packets,
+        &args.mcuboot,
+        &args.signature_file,
+        &args.erase_list,
+        &args.delta,
+        &args.delta_list,
+    ];
+    outputs.extend(specs.iter());
+    route_status(&outputs, args.format == SummaryFormat::Json);
+    // The metadata header, an embedded signature, and the encryption all
+    // depend on '--append-crc', so are only built the one way
+    let other_crc = args
+        .output
+        .iter()
+        .any(|x| x.append_crc.is_some_and(|x| x != args.append_crc));
+    if other_crc && (args.metadata.is_some() || args.sign.is_some() || args.encrypt.is_some()) {
+        exit_with_error(Error::Output(
+            "'crc' and 'nocrc' outputs cannot be combined with '--metadata', '--sign', or '--encrypt' (see '--append-crc')"
+                .to_string(),
+        ));
+    }
+    if let Some(prefix) = &args.keygen {
+        let key = SigningKey::generate(args.sign_alg);
+        let private = format!("{}.pem", prefix);
+        let public = format!("{}.pub.pem", prefix);
+        // Check both paths first, so that no half of a pair is written
+        if let Some(path) = [&private, &public]
+            .into_iter()
+            .find(|x| std::path::Path::new(x).exists())
+        {
+            exit_with_error(Error::Signature(format!("'{}' already exists", path)));
         }
-        if args.verbose > 1 {
-            for p in packets.iter() {
-                println!(
-                    " - Packet: ADDR = {:08x}, SIZE = {}, CRC16 = 0x{:04X}",
-                    p.address(),
-                    p.len(),
-                    p.crc16()
+        sign::write_key_file(std::path::Path::new(&private), &key.to_pem(), true)
+            .unwrap_or_else(|e| exit_with_error(e));
+        sign::write_key_file(
+            std::path::Path::new(&public),
+            &key.verifying_key().to_pem(),
+            false,
+        )
+        .unwrap_or_else(|e| exit_with_error(e));
+        status!("\nGenerated {} key pair:", args.sign_alg.name());
+        status!(" - Private: '{}'", private);
+        status!(" - Public:  '{}'", public);
+        return;
+    }
+    let encryption = args.encrypt.as_ref().map(|path| {
+        let key = encrypt::read_key_file(std::path::Path::new(path))
+            .unwrap_or_else(|e| exit_with_error(e));
+        Encryption::new(key, args.cipher_mode, args.iv)
+    });
+    let signer = args.sign.as_ref().map(|path| {
+        let key = SigningKey::from_file(std::path::Path::new(path), args.sign_alg)
+            .unwrap_or_else(|e| exit_with_error(e));
+        Signer {
+            key,
+            digest: args.sign_digest,
+            address: args.signature_address,
+        }
+    });
+    let device = args
+        .device
+        .as_ref()
+        .map(|name| Device::load(name).unwrap_or_else(|e| exit_with_error(e)));
+    // Checked here, rather than by clap, as the device may be from the config file
+    if device.is_none() && (args.erase_list.is_some() || args.erase_packets) {
+        exit_with_error(Error::Device(
+            "'--erase-list' and '--erase-packets' require a device profile ('-d')".to_string(),
+        ));
+    }
+    let packet_output = args.packets.is_some()
+        || args
+            .output
+            .iter()
+            .any(|x| x.format == output::OutputFormat::Packets);
+    if args.erase_packets && !packet_output {
+        exit_with_error(Error::Output(
+            "'--erase-packets' requires a packets output ('-p', or '-o packets:PATH')".to_string(),
+        ));
+    }
+    let path = args.file;
+    let data = stdio::read_input_text(std::path::Path::new(&path)).unwrap_or_else(|e| {
+        exit_with_error(Error::Input(format!("cannot read '{}' ({})", path, e)))
+    });
+    let records = input::read_records(&data);
+    let start = input::start_address(&records);
+    let (records, overlaps) =
+        overlap::resolve_overlaps(&records, args.overlap).unwrap_or_else(|e| exit_with_error(e));
+    if !overlaps.is_empty() {
+        status!("\nFound {} overlapping HEX records", overlaps.len());
+        if args.verbose > 0 {
+            for x in overlaps.iter() {
+                status!(
+                    " - Overlap: LINE {} ({}) and LINE {} ({}){}",
+                    x.first_line,
+                    x.first,
+                    x.second_line,
+                    x.second,
+                    if x.identical { ", identical" } else { "" }
                 );
             }
         }
-        packets
+    }
+    let mut records: Vec<ihex::Record> = records.into_iter().map(|(_, r)| r).collect();
+    let regions = Region::build_regions(&mut records);
+    if !regions.is_empty() {
+        status!("\nFound {} HEX regions", regions.len());
+    }
+    if args.verbose > 0 {
+        commands::print_regions(&regions);
+    }
+
+    let regions = if args.include_range.is_empty() && args.exclude_range.is_empty() {
+        regions
+    } else {
+        let regions = filter_regions(&regions, &args.include_range, &args.exclude_range);
+        status!("\nFiltered to {} HEX regions", regions.len());
+        if args.verbose > 0 {
+            commands::print_regions(&regions);
+        }
+        regions
+    };
+
+    let offset = match (args.offset, args.relocate) {
+        (Some(offset), _) => offset,
+        (None, Some(address)) => relocation_offset(&regions, address),
+        (None, None) => 0,
+    };
+    let regions = if offset == 0 {
+        regions
     } else {
-        let packets = make_packets(&mut regions);
-        if args.verbose > 0 && !packets.is_empty() {
-            println!("\nFound {} HEX packets", packets.len());
-        }
-        if args.verbose > 1 {
-            for p in packets.iter() {
-                println!(
-                    " - Packet: ADDR = {:08x}, SIZE = {}, CRC16 = 0x{:04X}",
+        let regions = offset_regions(&regions, offset).unwrap_or_else(|e| exit_with_error(e));
+        status!(
+            "\nMoved HEX regions by {}0x{:x}",
+            if offset < 0 { "-" } else { "+" },
+            offset.unsigned_abs()
+        );
+        if args.verbose > 0 {
+            commands::print_regions(&regions);
+        }
+        regions
+    };
+
+    let patches = patch::collect_patches(&args.set, &args.set_u32, &args.patch_file, args.endian)
+        .unwrap_or_else(|e| exit_with_error(e));
+    let regions = if patches.is_empty() {
+        regions
+    } else {
+        let mut regions = regions;
+        patch::apply_patches(&mut regions, &patches, args.extend)
+            .unwrap_or_else(|e| exit_with_error(e));
+        status!("\nApplied {} patches", patches.len());
+        if args.verbose > 0 {
+            for p in patches.iter() {
+                let bytes: Vec<String> = p.bytes().iter().map(|x| format!("{:02x}", x)).collect();
+                status!(
+                    " - Patch: ADDR = {:08x}, SIZE = {}, BYTES = {}",
                     p.address(),
                     p.len(),
-                    p.crc16()
+                    bytes.join(" ")
                 );
             }
         }
-        packets
+        regions
+    };
+
+    let unmerged = regions.clone();
+
+    let target = match (args.granularity, &device) {
+        (Some(target), _) => target,
+        (None, Some(device)) => device.target(),
+        (None, None) => Target::default(),
+    };
+    if args.verbose > 0 {
+        status!("\nProgram granularity: {} bytes", target.granularity());
+    }
+
+    if let Some(path) = &args.verify_signature {
+        let key = VerifyingKey::from_file(std::path::Path::new(path), args.sign_alg)
+            .unwrap_or_else(|e| exit_with_error(e));
+        let update = make_update(&regions, &target, args.append_crc);
+        let (image, signature) = match (args.signature_address, &args.signature_file) {
+            (Some(address), _) => {
+                sign::split_signature(&update, address).unwrap_or_else(|e| exit_with_error(e))
+            }
+            (None, Some(path)) => {
+                let signature = stdio::read_input(std::path::Path::new(path)).unwrap_or_else(|e| {
+                    exit_with_error(Error::Signature(format!("cannot read '{}' ({})", path, e)))
+                });
+                (update.to_vec(), signature)
+            }
+            (None, None) => exit_with_error(Error::Signature(
+                "no signature (see '--signature-address', or '--signature-file')".to_string(),
+            )),
+        };
+        key.verify(
+            &sign::signed_message(&image, args.sign_digest),
+            &signature,
+            args.sign_digest,
+        )
+        .unwrap_or_else(|e| exit_with_error(e));
+        status!("\nSignature verified ({}):", args.sign_alg.name());
+        status!(" - Length: {}", image.len());
+        return;
+    }
+
+    let metadata = args.metadata.as_ref().map(|path| {
+        let layout = MetadataLayout::from_file(std::path::Path::new(path))
+            .unwrap_or_else(|e| exit_with_error(e));
+        let address = match args.metadata_address.or(layout.address()) {
+            Some(address) => address,
+            None => exit_with_error(Error::Metadata(
+                "no header address (see '--metadata-address')".to_string(),
+            )),
+        };
+        let values = MetadataValues {
+            version: args.fw_version,
+            timestamp: args.timestamp.unwrap_or_else(metadata::build_timestamp),
+            git_hash: args.git_hash.clone(),
+        };
+        Metadata {
+            layout,
+            address,
+            values,
+            overwrite: args.metadata_overwrite,
+        }
+    });
+    let regions = match &metadata {
+        Some(metadata) => {
+            let mut regions = regions;
+            metadata
+                .apply(&mut regions, &target, args.append_crc)
+                .unwrap_or_else(|e| exit_with_error(e));
+            status!("\nMetadata header:");
+            status!(
+                " - ADDR = {:08x}, SIZE = {}",
+                metadata.address,
+                metadata.layout.size()
+            );
+            regions
+        }
+        None => regions,
+    };
+    let mut regions = regions;
+    let embedded = match &signer {
+        Some(signer) => signer
+            .embed(&mut regions, &target, args.append_crc)
+            .unwrap_or_else(|e| exit_with_error(e)),
+        None => None,
+    };
+    let unmerged_final = regions.clone();
+    let regions = merge_regions(&regions, &target);
+    if args.verbose > 0 {
+        if !regions.is_empty() {
+            status!("\nFound {} HEX regions", regions.len());
+        }
+        commands::print_regions(&regions);
+    }
+
+    let plan = device.as_ref().map(|device| {
+        let plan = ErasePlan::new(device, &regions).unwrap_or_else(|e| exit_with_error(e));
+        commands::report_erase_plan(&plan, device, args.verbose);
+        plan
+    });
+
+    let packets = match Region::single_region(&regions) {
+        Some(r) => {
+            //
+            //  M O N O  !!
+            //
+            status!("\nBuild HEX mono-region");
+            status!(" - Region: ADDR = {:08x}, SIZE = {}", r.address(), r.len());
+            r.to_packets(&target, !args.append_crc)
+        }
+        None => make_packets(&regions, &target),
     };
+    if args.verbose > 0 && !packets.is_empty() {
+        status!("\nFound {} HEX packets", packets.len());
+    }
+    if args.verbose > 1 {
+        packets.iter().for_each(commands::print_packet);
+    }
 
     let update = FirmwareUpdate::new(packets);
-    println!("\nFirmware update:");
-    println!(" - Length: {}", update.len());
-    println!(" - CRC32:  0x{:08X}", update.crc32());
-    println!();
+
+    // Sign the plaintext, before any encryption
+    let signature = match (&signer, embedded) {
+        (Some(_), Some(signature)) => Some(signature),
+        (Some(signer), None) => Some(signer.sign(&update.to_vec())),
+        (None, _) => None,
+    };
+    let update = match &encryption {
+        Some(encryption) => encryption.encrypt_update(&update, &target, args.append_crc),
+        None => update,
+    };
+    let mut summary = Summary::new(
+        &path,
+        &unmerged_final,
+        &regions,
+        &update,
+        &target,
+        args.append_crc,
+    );
+
+    commands::report_update(&update, &args.digest);
+
+    if let Some(encryption) = &encryption {
+        status!("Encryption ({}):", encryption.name());
+        status!(" - IV: {}", digest::to_hex_string(encryption.iv()));
+        status!();
+    }
+    if let (Some(signer), Some(signature)) = (&signer, &signature) {
+        status!("Signature ({}):", signer.key.alg().name());
+        match signer.address {
+            Some(address) => status!(" - ADDR = {:08x}, SIZE = {}", address, signature.len()),
+            None => status!(" - Appended, SIZE = {}", signature.len()),
+        }
+        if args.verbose > 0 {
+            status!(" - {}", digest::to_hex_string(signature));
+        }
+        status!();
+    }
+
+    let mut trailer = match args.append_digest {
+        true => output::digest_trailer(&update, &args.digest),
+        false => Vec::new(),
+    };
+    if let (Some(signature), None) = (&signature, args.signature_address) {
+        trailer.extend(signature);
+    }
 
     if args.verbose > 1 {
-        println!("{}", to_include_text(&update, args.append_crc));
+        status!(
+            "{}",
+            to_include_text(&update, args.append_crc, &args.digest, &args.symbol_prefix)
+        );
     }
 
-    if let Some(filename) = args.include {
+    let specs = output::output_specs(
+        &args.include,
+        &args.binary,
+        &args.hex,
+        &args.packets,
+        &args.output,
+    );
+    let image = output::Image {
+        update: &update,
+        trailer: &trailer,
+        regions: &regions,
+        target: &target,
+        append_crc: args.append_crc,
+        digests: &args.digest,
+        append_digest: args.append_digest,
+        symbol_prefix: &args.symbol_prefix,
+        start,
+    };
+    commands::write_outputs(&specs, &image, &mut summary, args.verbose);
+    let installed = match (&args.installed, &args.installed_crc16) {
+        (Some(path), _) => {
+            let old = input::read_hex_file(std::path::Path::new(path), args.overlap)
+                .unwrap_or_else(|e| exit_with_error(e));
+            let old = make_update(&old, &target, args.append_crc).to_region();
+            Some(delta::Installed::Image(
+                old.unwrap_or_else(|| Region::new(0)),
+            ))
+        }
+        (None, Some(path)) => Some(delta::Installed::Crc16s(
+            delta::read_crc16_list(std::path::Path::new(path))
+                .unwrap_or_else(|e| exit_with_error(e)),
+        )),
+        (None, None) => None,
+    };
+    let (packets, kept) = match (&installed, update.to_region()) {
+        (Some(installed), Some(image)) => {
+            let device = match args.page_size {
+                Some(_) => None,
+                None => device.as_ref(),
+            };
+            let page_size = args.page_size.unwrap_or(delta::DEFAULT_PAGE_SIZE);
+            let pages = delta::pages(&image.range(), device, page_size);
+            let (mut packets, mut kept) =
+                delta::changed_packets(&update, installed, &pages, &target);
+            if let (Some(plan), true) = (&plan, args.erase_packets) {
+                // Re-program every page of the sectors that are erased
+                kept = delta::erased_pages(&pages, &kept, plan);
+                packets = delta::page_packets(&image, &kept, &target)
+                    .into_iter()
+                    .flat_map(|(_, x)| x)
+                    .collect();
+            }
+            status!("Skipping unchanged packets:");
+            status!(" - Pages:   {} of {}", kept.len(), pages.len());
+            status!(" - Packets: {}", packets.len());
+            status!();
+            (packets, Some(kept))
+        }
+        _ => (update.packets().to_vec(), None),
+    };
+    let packets = match (args.compress, update.to_region()) {
+        (Some(format), Some(image)) => commands::compress_packets(&image, format, &target),
+        _ => packets,
+    };
+    let mut stream = match &plan {
+        Some(plan) if args.erase_packets => commands::erase_packets(plan, kept.as_deref()),
+        _ => Vec::new(),
+    };
+    stream.extend(packets);
+    commands::write_packet_files(&specs, &stream, &mut summary, args.verbose);
+    if let Some(path) = &args.diff_from {
+        let old = input::read_hex_file(std::path::Path::new(path), args.overlap)
+            .unwrap_or_else(|e| exit_with_error(e));
+        let old = make_update(&old, &target, args.append_crc);
+        let pages = match args.page_size {
+            Some(_) => None,
+            None => device.as_ref(),
+        };
+        let page_size = args.page_size.unwrap_or(delta::DEFAULT_PAGE_SIZE);
+        let delta = Delta::new(&old, &update, pages, page_size, &target);
+        status!("\nDelta update from '{}':", path);
+        commands::report_delta(&delta, args.verbose);
+        commands::write_delta(&delta, &args.delta, &args.delta_list, args.verbose);
+        if let Some(filename) = &args.delta {
+            summary.add_output("delta", filename);
+        }
+        if let Some(filename) = &args.delta_list {
+            summary.add_output("delta-list", filename);
+        }
+    }
+    if let Some(filename) = args.mcuboot {
+        let header_size = u16::try_from(args.mcuboot_header_size).unwrap_or_else(|_| {
+            exit_with_error(Error::Mcuboot("header size exceeds 16 bits".to_string()))
+        });
+        let options = McubootOptions {
+            header_size,
+            version: args.mcuboot_version,
+            load_addr: args.mcuboot_load_addr,
+        };
+        let region = Region::single_region(&regions).unwrap_or_else(|| {
+            exit_with_error(Error::Mcuboot("no (contiguous) firmware data".to_string()))
+        });
+        let image =
+            mcuboot::to_mcuboot_region(&region, &options).unwrap_or_else(|e| exit_with_error(e));
+        let mcuboot = FirmwareUpdate::new(image.to_packets(&target, false));
+        status!("\nMCUboot image:");
+        status!(" - ADDR = {:08x}, SIZE = {}", image.address(), image.len());
         if args.verbose > 0 {
-            println!("\nWriting '{}'", &filename);
+            status!("\nWriting '{}'", &filename);
         }
-        to_include_file(&filename, &update, args.append_crc);
+        to_binary_file(&filename, &mcuboot, false, &[], &target);
+        summary.add_output("mcuboot", &filename);
     }
-    if let Some(filename) = args.binary {
+    if let (Some(filename), Some(signature)) = (&args.signature_file, &signature) {
         if args.verbose > 0 {
-            println!("\nWriting '{}'", &filename);
+            status!("\nWriting '{}'", filename);
         }
-        to_binary_file(&filename, &update, args.append_crc);
+        stdio::write_output(filename, signature).unwrap();
+        summary.add_output("signature", filename);
+    }
+    if let (Some(filename), Some(plan)) = (args.erase_list, &plan) {
+        if args.verbose > 0 {
+            status!("\nWriting '{}'", &filename);
+        }
+        stdio::write_output(&filename, plan.to_json()).unwrap();
+        summary.add_output("erase-list", &filename);
+    }
+
+    let units = match (args.batch_serials, &args.batch_csv, args.batch_address) {
+        (Some((first, last)), _, Some(address)) => {
+            Some(batch::serial_units(first, last, address, args.endian))
+        }
+        (None, Some(path), Some(address)) => Some(
+            batch::read_units_csv(std::path::Path::new(path), address, args.endian)
+                .unwrap_or_else(|e| exit_with_error(e)),
+        ),
+        _ => None,
+    };
+    if let Some(units) = units {
+        let options = batch::BatchOptions {
+            dir: args.batch_dir.into(),
+            name: args.batch_name,
+            formats: args.batch_format,
+            append_crc: args.append_crc,
+            digests: args.digest,
+            symbol_prefix: args.symbol_prefix,
+            append_digest: args.append_digest,
+            signer,
+            extend: args.extend,
+            metadata,
+            start,
+        };
+        let manifest = batch::run_batch(&unmerged, &units, &target, &options)
+            .unwrap_or_else(|e| exit_with_error(e));
+        let filename = options.dir.join("manifest.csv");
+        std::fs::write(&filename, batch::to_manifest_text(&manifest)).unwrap();
+        summary.add_output("manifest", filename.to_str().unwrap());
+        status!("\nBatch of {} units:", units.len());
+        status!(" - Files:    {}", manifest.len());
+        status!(" - Manifest: '{}'", filename.display());
+        if args.verbose > 0 {
+            for m in manifest.iter() {
+                status!(
+                    " - Unit: SERIAL = {}, FILE = '{}', CRC32 = 0x{:08X}",
+                    m.serial,
+                    m.file,
+                    m.crc32
+                );
+            }
+        }
+    }
+
+    if args.format == SummaryFormat::Json {
+        println!("{}", summary.to_json());
     }
 }